@@ -0,0 +1,225 @@
+use crate::gpt::Partition;
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    ffi::{OsStr, c_void},
+    fs, io,
+    os::fd::AsRawFd,
+    path, ptr, thread,
+    time::Duration,
+};
+
+const BLKRRPART: libc::c_ulong = 0x125f;
+const BLKDISCARD: libc::c_ulong = 0x1277;
+const BLKZEROOUT: libc::c_ulong = 0x127f;
+const BLKPG: libc::c_ulong = 0x1269;
+const BLKPG_ADD_PARTITION: libc::c_int = 1;
+const BLKPG_DEL_PARTITION: libc::c_int = 2;
+
+/// Partitions stay busy for a moment after writing, while udev probes them.
+const REREAD_RETRIES: usize = 10;
+const REREAD_DELAY: Duration = Duration::from_millis(500);
+
+const SG_IO: libc::c_ulong = 0x2285;
+const SG_DXFER_NONE: libc::c_int = -1;
+const SG_INFO_OK_MASK: libc::c_uint = 0x1;
+const SCSI_TIMEOUT_MS: libc::c_uint = 30_000;
+
+const ALLOW_MEDIUM_REMOVAL: [u8; 6] = [0x1e, 0, 0, 0, 0, 0];
+/// START STOP UNIT with LoEj set and Start clear.
+const EJECT: [u8; 6] = [0x1b, 0, 0, 0, 0x02, 0];
+
+/// `sg_io_hdr` of `<scsi/sg.h>`, missing from libc.
+#[repr(C)]
+struct SgIoHdr {
+    interface_id:    libc::c_int,
+    dxfer_direction: libc::c_int,
+    cmd_len:         libc::c_uchar,
+    mx_sb_len:       libc::c_uchar,
+    iovec_count:     libc::c_ushort,
+    dxfer_len:       libc::c_uint,
+    dxferp:          *mut c_void,
+    cmdp:            *const libc::c_uchar,
+    sbp:             *mut libc::c_uchar,
+    timeout:         libc::c_uint,
+    flags:           libc::c_uint,
+    pack_id:         libc::c_int,
+    usr_ptr:         *mut c_void,
+    status:          libc::c_uchar,
+    masked_status:   libc::c_uchar,
+    msg_status:      libc::c_uchar,
+    sb_len_wr:       libc::c_uchar,
+    host_status:     libc::c_ushort,
+    driver_status:   libc::c_ushort,
+    resid:           libc::c_int,
+    duration:        libc::c_uint,
+    info:            libc::c_uint,
+}
+
+/// `blkpg_partition` of `<linux/blkpg.h>`.
+#[repr(C)]
+struct BlkpgPartition {
+    start:   libc::c_longlong,
+    length:  libc::c_longlong,
+    pno:     libc::c_int,
+    devname: [libc::c_char; 64],
+    volname: [libc::c_char; 64],
+}
+
+/// `blkpg_ioctl_arg` of `<linux/blkpg.h>`.
+#[repr(C)]
+struct BlkpgIoctlArg {
+    op:      libc::c_int,
+    flags:   libc::c_int,
+    datalen: libc::c_int,
+    data:    *mut c_void,
+}
+
+/// Sends a SCSI command without data transfer through SG_IO.
+fn scsi_command(file: &fs::File, cdb: &[u8]) -> Result<()> {
+    let mut sense = [0u8; 32];
+    let mut hdr = SgIoHdr {
+        interface_id:    b'S' as libc::c_int,
+        dxfer_direction: SG_DXFER_NONE,
+        cmd_len:         cdb.len() as libc::c_uchar,
+        mx_sb_len:       sense.len() as libc::c_uchar,
+        iovec_count:     0,
+        dxfer_len:       0,
+        dxferp:          ptr::null_mut(),
+        cmdp:            cdb.as_ptr(),
+        sbp:             sense.as_mut_ptr(),
+        timeout:         SCSI_TIMEOUT_MS,
+        flags:           0,
+        pack_id:         0,
+        usr_ptr:         ptr::null_mut(),
+        status:          0,
+        masked_status:   0,
+        msg_status:      0,
+        sb_len_wr:       0,
+        host_status:     0,
+        driver_status:   0,
+        resid:           0,
+        duration:        0,
+        info:            0,
+    };
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), SG_IO as _, &mut hdr) } != 0 {
+        return Err(io::Error::last_os_error()).context("SG_IO failed");
+    }
+    if hdr.info & SG_INFO_OK_MASK != 0 {
+        // Sense key sits elsewhere in descriptor format
+        let key = match sense[0] & 0x7f {
+            0x72 | 0x73 => sense[1],
+            _ => sense[2],
+        } & 0x0f;
+        return Err(eyre!(
+            "SCSI command {cmd:#04x} failed, status {status:#04x}, sense key {key:#x}",
+            cmd = cdb[0],
+            status = hdr.status
+        ));
+    }
+    Ok(())
+}
+
+/// Flushes the device's write cache, then lets go of the medium and ejects it.
+pub fn eject(file: &fs::File) -> Result<()> {
+    file.sync_all().context("failed to flush device")?;
+    scsi_command(file, &ALLOW_MEDIUM_REMOVAL).context("failed to unlock medium")?;
+    scsi_command(file, &EJECT).context("failed to eject medium")
+}
+
+/// Makes the kernel read the partition table again, retrying while partitions are busy.
+pub fn reread_partitions(file: &fs::File) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKRRPART as _) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EBUSY) || attempt == REREAD_RETRIES {
+            return Err(e).context("BLKRRPART failed");
+        }
+        trace!("Partitions busy, retrying");
+        thread::sleep(REREAD_DELAY);
+        attempt += 1;
+    }
+}
+
+fn range_op(file: &fs::File, op: libc::c_ulong, start: usize, len: usize) -> Result<()> {
+    let range = [start as u64, len as u64];
+    if unsafe { libc::ioctl(file.as_raw_fd(), op as _, &range) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Zeroes the range, offloaded to the device when it supports write-zeroes. Otherwise the
+/// kernel writes the zeroes itself.
+pub fn zero_out(file: &fs::File, start: usize, len: usize) -> Result<()> {
+    range_op(file, BLKZEROOUT, start, len).context("BLKZEROOUT failed")
+}
+
+/// Discards the range, which reads back as zeroes only on some devices.
+pub fn discard(file: &fs::File, start: usize, len: usize) -> Result<()> {
+    range_op(file, BLKDISCARD, start, len).context("BLKDISCARD failed")
+}
+
+fn partition_op(file: &fs::File, op: libc::c_int, partition: &Partition) -> Result<()> {
+    let mut data = BlkpgPartition {
+        start:   partition.start as libc::c_longlong,
+        length:  partition.len as libc::c_longlong,
+        pno:     partition.number as libc::c_int,
+        devname: [0; 64],
+        volname: [0; 64],
+    };
+    let mut arg = BlkpgIoctlArg {
+        op,
+        flags: 0,
+        datalen: size_of::<BlkpgPartition>() as libc::c_int,
+        data: (&raw mut data).cast(),
+    };
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKPG as _, &mut arg) } != 0 {
+        return Err(io::Error::last_os_error()).context("BLKPG failed");
+    }
+    Ok(())
+}
+
+/// Partitions the kernel knows of, from sysfs. `name` is the disk's name under `/sys/block`.
+fn known_partitions(name: &OsStr) -> Result<Vec<Partition>> {
+    let sys = path::Path::new("/sys/block").join(name);
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir(&sys).context("failed to list partitions")? {
+        let dir = entry?.path();
+        let Ok(number) = fs::read_to_string(dir.join("partition")) else {
+            continue;
+        };
+        // Always in 512 byte units, whatever the sector size
+        let sectors = |attr: &str| -> Result<usize> {
+            let value = fs::read_to_string(dir.join(attr))?;
+            Ok(value.trim().parse::<usize>()? * 512)
+        };
+        partitions.push(Partition {
+            number: number.trim().parse().context("invalid partition number")?,
+            start:  sectors("start").context("invalid partition start")?,
+            len:    sectors("size").context("invalid partition size")?,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Brings the partitions the kernel knows of in line with given ones, one by one like `partx
+/// -u` does. Unchanged partitions are left alone, so they may stay in use.
+pub fn update_partitions(file: &fs::File, name: &OsStr, partitions: &[Partition]) -> Result<()> {
+    let known = known_partitions(name)?;
+
+    for old in known.iter().filter(|old| !partitions.contains(old)) {
+        partition_op(file, BLKPG_DEL_PARTITION, old)
+            .with_context(|| format!("failed to remove partition {}", old.number))?;
+    }
+    for new in partitions.iter().filter(|new| !known.contains(new)) {
+        partition_op(file, BLKPG_ADD_PARTITION, new)
+            .with_context(|| format!("failed to add partition {}", new.number))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use sha2::{Digest, digest::DynDigest};
+use std::{fs, path};
+
+/// Part of the image holding data, `start..end` in bytes.
+#[derive(Debug, Clone)]
+pub struct Range {
+    pub start:    usize,
+    pub end:      usize,
+    pub checksum: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumType {
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumType {
+    pub fn hasher(&self) -> Box<dyn DynDigest + Send> {
+        match self {
+            ChecksumType::Sha1 => Box::new(sha1::Sha1::new()),
+            ChecksumType::Sha256 => Box::new(sha2::Sha256::new()),
+        }
+    }
+}
+
+/// Mapped ranges of a sparse image, as described by `bmaptool` block map files.
+#[derive(Debug, Clone)]
+pub struct BlockMap {
+    pub image_size:    usize,
+    pub checksum_type: ChecksumType,
+    pub ranges:        Vec<Range>,
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|n| n.has_tag_name(name))
+        .and_then(|n| n.text())
+        .map(str::trim)
+}
+
+fn child_num(node: roxmltree::Node, name: &str) -> Result<usize> {
+    let text = child_text(node, name).ok_or_else(|| eyre!("bmap lacks {name}"))?;
+    text.parse()
+        .with_context(|| format!("invalid {name} {text:?}"))
+}
+
+impl BlockMap {
+    /// Looks for `image.img.xz.bmap`, then `image.img.bmap` and `image.bmap` next to the image.
+    pub fn find(image: &path::Path) -> Option<path::PathBuf> {
+        let mut base = image.to_path_buf();
+        for _ in 0..3 {
+            let mut candidate = base.clone().into_os_string();
+            candidate.push(".bmap");
+            let candidate = path::PathBuf::from(candidate);
+            if candidate.is_file() {
+                return Some(candidate);
+            }
+            base.extension()?;
+            base.set_extension("");
+        }
+        None
+    }
+
+    pub fn load(path: &path::Path) -> Result<Self> {
+        let text = fs::read_to_string(path).context("failed to read bmap")?;
+        let doc = roxmltree::Document::parse(&text).context("failed to parse bmap")?;
+        let root = doc.root_element();
+        if !root.has_tag_name("bmap") {
+            return Err(eyre!("Not a bmap file"));
+        }
+
+        let version = root.attribute("version").unwrap_or("1.0");
+        let major = version
+            .split('.')
+            .next()
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(0);
+        if !(1..=2).contains(&major) {
+            return Err(eyre!("Unsupported bmap version {version}"));
+        }
+
+        let checksum_type = match child_text(root, "ChecksumType") {
+            Some("sha256") => ChecksumType::Sha256,
+            Some("sha1") | None => ChecksumType::Sha1,
+            Some(other) => return Err(eyre!("Unsupported bmap checksum type {other}")),
+        };
+
+        let file_checksum = child_text(root, "BmapFileChecksum")
+            .or_else(|| child_text(root, "BmapFileSHA1"))
+            .filter(|sum| !sum.is_empty());
+        if let Some(expected) = file_checksum {
+            // Checksum is computed with its own value replaced by zeros
+            let zeroed = text.replacen(expected, &"0".repeat(expected.len()), 1);
+            let mut hasher = checksum_type.hasher();
+            hasher.update(zeroed.as_bytes());
+            if hex::encode(hasher.finalize()) != expected.to_ascii_lowercase() {
+                return Err(eyre!("bmap file checksum mismatch"));
+            }
+        }
+
+        let image_size = child_num(root, "ImageSize")?;
+        let block_size = child_num(root, "BlockSize")?;
+        let blocks = child_num(root, "BlocksCount")?;
+        if block_size == 0 || blocks != image_size.div_ceil(block_size) {
+            return Err(eyre!("Inconsistent bmap geometry"));
+        }
+
+        let block_map = root
+            .children()
+            .find(|n| n.has_tag_name("BlockMap"))
+            .ok_or_else(|| eyre!("bmap lacks BlockMap"))?;
+
+        let mut ranges: Vec<Range> = Vec::new();
+        for node in block_map.children().filter(|n| n.has_tag_name("Range")) {
+            let text = node.text().unwrap_or_default().trim();
+            let (first, last) = text.split_once('-').unwrap_or((text, text));
+            let first: usize = first
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid range {text:?}"))?;
+            let last: usize = last
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid range {text:?}"))?;
+            if last < first || last >= blocks {
+                return Err(eyre!("bmap range {text:?} out of image"));
+            }
+            let (Some(start), Some(end)) = (
+                first.checked_mul(block_size),
+                (last + 1).checked_mul(block_size),
+            ) else {
+                return Err(eyre!("bmap range {text:?} out of image"));
+            };
+            if ranges.last().is_some_and(|prev| prev.end > start) {
+                return Err(eyre!("bmap ranges not sorted"));
+            }
+
+            let checksum = node
+                .attribute("chksum")
+                .or_else(|| node.attribute("sha1"))
+                .map(hex::decode)
+                .transpose()
+                .with_context(|| format!("invalid checksum of range {text:?}"))?;
+
+            ranges.push(Range {
+                start,
+                end: end.min(image_size),
+                checksum,
+            });
+        }
+
+        let map = Self {
+            image_size,
+            checksum_type,
+            ranges,
+        };
+        debug!(
+            "bmap {version}: {mapped} of {image_size} bytes mapped in {count} ranges",
+            mapped = map.mapped(),
+            count = map.ranges.len()
+        );
+        Ok(map)
+    }
+
+    pub fn mapped(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    const BMAP: &str = r#"<?xml version="1.0" ?>
+<bmap version="2.0">
+    <ImageSize> 10000 </ImageSize>
+    <BlockSize> 4096 </BlockSize>
+    <BlocksCount> 3 </BlocksCount>
+    <MappedBlocksCount> 2 </MappedBlocksCount>
+    <ChecksumType> sha256 </ChecksumType>
+    <BmapFileChecksum> 0000000000000000000000000000000000000000000000000000000000000000 </BmapFileChecksum>
+    <BlockMap>
+        <Range chksum="00ff"> 0 </Range>
+        <Range> 2-2 </Range>
+    </BlockMap>
+</bmap>
+"#;
+
+    /// Block map with its own checksum filled in.
+    fn sign(text: &str) -> String {
+        let sum = hex::encode(sha2::Sha256::digest(text));
+        text.replacen(&"0".repeat(64), &sum, 1)
+    }
+
+    fn bmap() -> String { sign(BMAP) }
+
+    fn load(text: &str) -> Result<BlockMap> { BlockMap::load(temp_file(text.as_bytes()).path()) }
+
+    #[test]
+    fn loads_ranges() {
+        let map = load(&bmap()).unwrap();
+        assert_eq!(map.image_size, 10000);
+        assert_eq!(map.checksum_type, ChecksumType::Sha256);
+        let ranges: Vec<_> = map
+            .ranges
+            .iter()
+            .map(|range| (range.start, range.end, range.checksum.clone()))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(0, 4096, Some(vec![0x00, 0xFF])), (8192, 10000, None)]
+        );
+        assert_eq!(map.mapped(), 4096 + 1808);
+    }
+
+    #[test]
+    fn rejects_damaged_bmap() {
+        let bmap = bmap();
+        assert!(load(&bmap[..bmap.len() / 2]).is_err());
+        assert!(load(&bmap.replace("2-2", "2-3")).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        for (from, to) in [
+            ("2-2", "2-3"),
+            ("2-2", "1-0"),
+            ("<Range chksum=\"00ff\"> 0 </Range>", "<Range> 2 </Range>"),
+        ] {
+            let text = sign(&BMAP.replace(from, to));
+            assert!(load(&text).unwrap_err().to_string().contains("bmap range"));
+        }
+
+        // Ranges past what the address space holds
+        let text = BMAP
+            .replace("10000", &usize::MAX.to_string())
+            .replace("4096", &(1usize << 63).to_string())
+            .replace("> 3 <", "> 2 <")
+            .replace("2-2", "1");
+        let err = load(&sign(&text)).unwrap_err().to_string();
+        assert!(err.contains("out of image"));
+    }
+}
@@ -0,0 +1,226 @@
+use crate::reader::by_ext;
+use clap::Parser;
+use color_eyre::eyre::{Result, eyre};
+use std::{path, sync::OnceLock};
+
+static ARGS: OnceLock<Args> = OnceLock::new();
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Disk image to write, optionally compressed or packed in an archive
+    pub image: path::PathBuf,
+
+    /// Target device (like `/dev/sdb` or `/dev/disk/by-id/usb-...`) instead of picking among
+    /// detected ones. Repeat to write several devices at once
+    #[arg(short, long, value_name = "PATH")]
+    pub device: Vec<path::PathBuf>,
+
+    /// Serial number of the target device, instead of picking among detected ones. Repeat to
+    /// write several devices at once
+    #[arg(short, long, conflicts_with = "device")]
+    pub serial: Vec<String>,
+
+    /// Write all detected devices at once
+    #[arg(short, long, conflicts_with_all = ["device", "serial"])]
+    pub all: bool,
+
+    /// Allow writing the disk holding the running system, when named by --device or --serial.
+    /// Almost certainly a mistake
+    #[arg(long)]
+    pub allow_system_disk: bool,
+
+    /// Never write the device with given serial number, on top of those listed in
+    /// `/etc/image_writer_rs/denylist`. Repeat for more devices
+    #[arg(long, value_name = "SERIAL")]
+    pub deny_serial: Vec<String>,
+
+    /// Wait for devices to be plugged in and write each of them, until interrupted. Devices
+    /// present at start are left alone
+    #[arg(short, long, conflicts_with_all = ["device", "serial", "all"])]
+    pub wait: bool,
+
+    /// Only write devices with vendor containing given text, when waiting for them
+    #[arg(long, value_name = "TEXT", requires = "wait")]
+    pub vendor: Option<String>,
+
+    /// Only write devices with model containing given text, when waiting for them
+    #[arg(long, value_name = "TEXT", requires = "wait")]
+    pub model: Option<String>,
+
+    /// Only write devices at least this large (like `8G`, decimal units), when waiting for them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "wait")]
+    pub min_size: Option<usize>,
+
+    /// Only write devices at most this large (like `64G`, decimal units), when waiting for them
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "wait")]
+    pub max_size: Option<usize>,
+
+    /// Start writing right away, without the countdown
+    #[arg(short, long, conflicts_with = "countdown")]
+    pub yes: bool,
+
+    /// Never ask: fail on anything not decided by options, start without the countdown
+    #[arg(long, conflicts_with = "countdown")]
+    pub non_interactive: bool,
+
+    /// Write images lacking the MBR boot signature without asking
+    #[arg(long)]
+    pub accept_non_bootable: bool,
+
+    /// Archive member to write, when the archive holds several files
+    #[arg(short, long, value_name = "NAME")]
+    pub member: Option<String>,
+
+    /// Unmount partitions and disable swap on the target devices, instead of refusing them
+    #[arg(long)]
+    pub unmount: bool,
+
+    /// Move the backup GPT to the end of media without asking
+    #[arg(long)]
+    pub fix_gpt: bool,
+
+    /// Seconds to wait before overwriting the device
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub countdown: u64,
+
+    /// Zero the devices by write-zeroes or discard first, then skip writing blocks of zeroes.
+    /// Devices not supporting either are written in full
+    #[arg(long)]
+    pub sparse: bool,
+
+    /// Read each block from the devices first and write only those differing from the image,
+    /// saving time and flash wear when the devices hold an older version of it
+    #[arg(long, conflicts_with = "sparse")]
+    pub incremental: bool,
+
+    /// Continue interrupted writes of this image without asking. Raw images are read from where
+    /// the write stopped, for others the data written before is compared with the image
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Skip reading back the written data
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Eject the devices once written and verified, so they can be pulled out safely
+    #[arg(long)]
+    pub eject: bool,
+
+    /// Eject the devices and power off their USB ports once written and verified, like
+    /// `udisksctl power-off`
+    #[arg(long)]
+    pub power_off: bool,
+
+    /// Logging verbosity: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL", default_value = "info")]
+    pub log_level: log::LevelFilter,
+
+    /// Image format given as file extension (like `xz`, `qcow2` or `tar.gz`), instead of
+    /// detecting it
+    #[arg(short, long, value_name = "EXT", value_parser = parse_format)]
+    pub format: Option<String>,
+}
+
+fn parse_format(format: &str) -> Result<String> {
+    let format = format.trim_start_matches('.').to_ascii_uppercase();
+    by_ext(&format)?;
+    Ok(format)
+}
+
+/// Parses sizes like `8G` or `500MB`, in decimal units as printed on the sticks.
+fn parse_size(size: &str) -> Result<usize> {
+    let (digits, unit) = size.split_at(
+        size.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len()),
+    );
+    let unit = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1000,
+        "M" => 1000 * 1000,
+        "G" => 1000 * 1000 * 1000,
+        "T" => 1000 * 1000 * 1000 * 1000,
+        _ => return Err(eyre!("unknown unit {unit:?}")),
+    };
+    Ok(digits.parse::<usize>()? * unit)
+}
+
+/// Parses and validates the command line. Help and version requests exit right away.
+pub fn init() -> Result<()> {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            // Drop the usage and hints, some errors list the arguments on lines of their own
+            let message = e.render().to_string();
+            let message = message
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .map(str::trim)
+                .collect::<Vec<_>>()
+                .join(" ");
+            return Err(eyre!("{}", message.trim_start_matches("error: ")));
+        },
+    };
+
+    if !args.image.is_file() {
+        return Err(eyre!("Image {:?} is not a file", args.image));
+    }
+    if let Some(device) = args.device.iter().find(|device| !device.exists()) {
+        return Err(eyre!("Device {device:?} doesn't exist"));
+    }
+
+    if args.non_interactive
+        && args.device.is_empty()
+        && args.serial.is_empty()
+        && !args.all
+        && !args.wait
+    {
+        return Err(eyre!(
+            "Non-interactive mode needs the devices given by --device, --serial, --all or --wait"
+        ));
+    }
+
+    ARGS.set(args)
+        .map_err(|_| eyre!("Arguments already parsed"))
+}
+
+pub fn args() -> &'static Args { ARGS.get().expect("arguments not parsed") }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(["image_writer_rs"].iter().chain(args))
+    }
+
+    #[test]
+    fn parses_format_as_extension() {
+        let args = parse(&["disk", "-f", ".tar.gz"]).unwrap();
+        assert_eq!(args.format.as_deref(), Some("TAR.GZ"));
+        assert!(parse(&["disk", "--format", "doc"]).is_err());
+    }
+
+    #[test]
+    fn rejects_countdown_with_yes() {
+        assert!(parse(&["disk", "-y", "--countdown", "3"]).is_err());
+        assert_eq!(parse(&["disk"]).unwrap().countdown, 10);
+    }
+
+    #[test]
+    fn parses_decimal_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("8G").unwrap(), 8_000_000_000);
+        assert_eq!(parse_size("64gb").unwrap(), 64_000_000_000);
+        assert_eq!(parse_size("1T").unwrap(), 1_000_000_000_000);
+    }
+
+    #[test]
+    fn rejects_incomplete_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("8X").is_err());
+        assert!(parse_size("8G4").is_err());
+    }
+}
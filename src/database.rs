@@ -1,11 +1,6 @@
-use color_eyre::eyre::{Context, Result, eyre};
+use color_eyre::eyre::Result;
 use log::debug;
-use std::{
-    collections::BTreeMap,
-    ffi::{OsStr, OsString},
-    fs, path,
-    path::Path,
-};
+use std::{collections::BTreeMap, ffi::OsStr, fs, path, path::Path};
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct Database {
@@ -0,0 +1,155 @@
+use crate::tools::eyre_unroll;
+use color_eyre::Report;
+use std::fmt;
+
+/// Reason the image was not written, each with its own process exit code.
+#[derive(Debug)]
+pub enum Error {
+    /// Unexpected internal failure, exit code 1.
+    Other(Report),
+    /// Invalid command line, exit code 2.
+    Usage(Report),
+    /// No usable target device found, or the given one is unusable or in use, exit code 3.
+    NoDevice(Report),
+    /// Image unreadable, corrupted or of unsupported format, exit code 4.
+    BadImage(Report),
+    /// Image larger than the target device, exit code 5.
+    TooLarge { image: usize, device: usize },
+    /// User declined to continue at a prompt, exit code 6.
+    Aborted,
+    /// Target device could not be opened or written to, exit code 7.
+    Write(Report),
+    /// Data read back from the target differs from the image, exit code 8.
+    Verification,
+    /// Backup GPT could not be moved to the end of media, exit code 9.
+    Gpt(Report),
+    /// Some of the devices failed while the others were written fine, exit code 10.
+    Partial { failed: usize, total: usize },
+    /// Interrupted by the user while writing or verifying, leaving the devices inconsistent, exit
+    /// code 11.
+    Cancelled,
+}
+
+impl Error {
+    pub fn code(&self) -> u8 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Usage(_) => 2,
+            Error::NoDevice(_) => 3,
+            Error::BadImage(_) => 4,
+            Error::TooLarge { .. } => 5,
+            Error::Aborted => 6,
+            Error::Write(_) => 7,
+            Error::Verification => 8,
+            Error::Gpt(_) => 9,
+            Error::Partial { .. } => 10,
+            Error::Cancelled => 11,
+        }
+    }
+
+    /// Keeps the user's decision to abort, even when reported through some other failure.
+    fn or_aborted(self) -> Self {
+        let aborted = match &self {
+            Error::Other(e)
+            | Error::Usage(e)
+            | Error::NoDevice(e)
+            | Error::BadImage(e)
+            | Error::Write(e)
+            | Error::Gpt(e) => e
+                .chain()
+                .any(|cause| matches!(cause.downcast_ref::<Error>(), Some(Error::Aborted))),
+            _ => false,
+        };
+        if aborted { Error::Aborted } else { self }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Other(e)
+            | Error::Usage(e)
+            | Error::NoDevice(e)
+            | Error::BadImage(e)
+            | Error::Write(e)
+            | Error::Gpt(e) => f.write_str(&eyre_unroll(e)),
+            Error::TooLarge { image, device } => {
+                write!(f, "Image won't fit on media ({image} > {device} bytes)")
+            },
+            Error::Aborted => f.write_str("Aborted by user"),
+            Error::Verification => f.write_str("Target verification failed"),
+            Error::Partial { failed, total } => {
+                write!(f, "Writing failed on {failed} of {total} devices")
+            },
+            Error::Cancelled => f.write_str("Interrupted, devices left in an inconsistent state"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Report> for Error {
+    fn from(e: Report) -> Self { Error::Other(e).or_aborted() }
+}
+
+pub trait ResultExt<T> {
+    /// Classifies the failure as `kind`, unless the user aborted.
+    fn fail_as(self, kind: fn(Report) -> Error) -> Result<T, Error>;
+}
+
+impl<T> ResultExt<T> for color_eyre::Result<T> {
+    fn fail_as(self, kind: fn(Report) -> Error) -> Result<T, Error> {
+        self.map_err(|e| kind(e).or_aborted())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color_eyre::eyre::{WrapErr, eyre};
+
+    #[test]
+    fn codes_are_distinct() {
+        let errors = [
+            Error::Other(eyre!("other")),
+            Error::Usage(eyre!("usage")),
+            Error::NoDevice(eyre!("device")),
+            Error::BadImage(eyre!("image")),
+            Error::TooLarge {
+                image:  2,
+                device: 1,
+            },
+            Error::Aborted,
+            Error::Write(eyre!("write")),
+            Error::Verification,
+            Error::Gpt(eyre!("gpt")),
+            Error::Partial {
+                failed: 1,
+                total:  2,
+            },
+            Error::Cancelled,
+        ];
+        let codes: Vec<u8> = errors.iter().map(Error::code).collect();
+        assert_eq!(codes, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn keeps_abort_through_other_failures() {
+        let wrapped: color_eyre::Result<()> =
+            Err(Report::new(Error::Aborted)).wrap_err("failed to prepare image");
+        assert!(matches!(
+            wrapped.fail_as(Error::BadImage),
+            Err(Error::Aborted)
+        ));
+        assert!(matches!(
+            Error::from(Report::new(Error::Aborted)),
+            Error::Aborted
+        ));
+
+        let failed: color_eyre::Result<()> = Err(eyre!("truncated image"));
+        assert!(matches!(
+            failed.fail_as(Error::BadImage),
+            Err(Error::BadImage(_))
+        ));
+    }
+}
@@ -0,0 +1,524 @@
+use crate::tools::{AlignedBuffer, le_u32, le_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{fs, os::unix::fs::FileExt};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const PROTECTIVE_TYPE: u8 = 0xEE;
+const EXTENDED_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Cap on the chain of extended boot records, which may well loop.
+const MAX_EBRS: usize = 100;
+
+const HDR_CRC: usize = 16;
+const HDR_MY_LBA: usize = 24;
+const HDR_ALTERNATE_LBA: usize = 32;
+const HDR_LAST_USABLE: usize = 48;
+const HDR_ENTRIES_LBA: usize = 72;
+const HDR_ENTRIES_NUM: usize = 80;
+const HDR_ENTRY_SIZE: usize = 84;
+const HDR_ENTRIES_CRC: usize = 88;
+
+/// Partition as numbered by the kernel, with its placement in bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub number: usize,
+    pub start:  usize,
+    pub len:    usize,
+}
+
+/// Block of sectors to be written over the target.
+pub struct Patch {
+    pub offset: usize,
+    pub data:   Vec<u8>,
+}
+
+/// Set of patches moving the backup GPT to the last LBA of the media.
+pub struct Relocation {
+    pub patches: Vec<Patch>,
+}
+
+fn put_u32(buf: &mut [u8], at: usize, val: u32) {
+    buf[at..at + 4].copy_from_slice(&val.to_le_bytes())
+}
+
+fn put_u64(buf: &mut [u8], at: usize, val: u64) {
+    buf[at..at + 8].copy_from_slice(&val.to_le_bytes())
+}
+
+fn header_crc(header: &[u8], size: usize) -> u32 {
+    let mut hdr = header[..size].to_vec();
+    put_u32(&mut hdr, HDR_CRC, 0);
+    crc32fast::hash(&hdr)
+}
+
+fn read_at(dev: &fs::File, offset: usize, len: usize) -> Result<Vec<u8>> {
+    let mut buf = AlignedBuffer::new();
+    let aligned_buf = buf.get_aligned_buf();
+    if len > aligned_buf.len() {
+        return Err(eyre!("GPT structure too large ({len} bytes)"));
+    }
+    dev.read_exact_at(&mut aligned_buf[..len], offset as u64)
+        .context("failed to read GPT")?;
+    Ok(aligned_buf[..len].to_vec())
+}
+
+/// Primary GPT header with its partition entries.
+struct Table {
+    header:      Vec<u8>,
+    header_size: usize,
+    entries:     Vec<u8>,
+}
+
+/// Reads the primary GPT, checking its checksums. Returns `None` if there's no GPT.
+fn read_gpt(dev: &fs::File, sector: usize) -> Result<Option<Table>> {
+    let header = read_at(dev, sector, sector)?;
+    if &header[..8] != SIGNATURE {
+        debug!("No GPT signature found");
+        return Ok(None);
+    }
+
+    let header_size = le_u32(&header, 12) as usize;
+    if !(MIN_HEADER_SIZE..=sector).contains(&header_size) {
+        return Err(eyre!("Invalid GPT header size {header_size}"));
+    }
+    if header_crc(&header, header_size) != le_u32(&header, HDR_CRC) {
+        return Err(eyre!("Primary GPT header checksum mismatch"));
+    }
+
+    let entries_lba = le_u64(&header, HDR_ENTRIES_LBA) as usize;
+    let entries_len =
+        le_u32(&header, HDR_ENTRIES_NUM) as usize * le_u32(&header, HDR_ENTRY_SIZE) as usize;
+    let entries_sectors = entries_len.div_ceil(sector);
+
+    let mut entries = read_at(dev, entries_lba * sector, entries_sectors * sector)?;
+    if crc32fast::hash(&entries[..entries_len]) != le_u32(&header, HDR_ENTRIES_CRC) {
+        return Err(eyre!("GPT partition entries checksum mismatch"));
+    }
+    entries.truncate(entries_len);
+
+    Ok(Some(Table {
+        header,
+        header_size,
+        entries,
+    }))
+}
+
+/// Walks the chain of extended boot records starting at LBA `first`, the start of an extended
+/// partition. Logical partitions are numbered from `number` on, in chain order.
+fn logical_partitions(
+    dev: &fs::File,
+    sector: usize,
+    first: usize,
+    number: &mut usize,
+) -> Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut ebr_lba = first;
+    for _ in 0..MAX_EBRS {
+        let ebr = read_at(dev, ebr_lba * sector, sector)?;
+        if ebr[510..512] != [0x55, 0xAA] {
+            debug!("No signature in extended boot record at LBA {ebr_lba}");
+            break;
+        }
+        let entries = || {
+            ebr[446..510]
+                .chunks_exact(16)
+                .filter(|entry| le_u32(entry, 12) != 0)
+        };
+
+        // Data partition starts relative to its own record, the next record relative to
+        // the extended partition
+        for entry in entries().filter(|entry| !EXTENDED_TYPES.contains(&entry[4])) {
+            partitions.push(Partition {
+                number: *number,
+                start:  (ebr_lba + le_u32(entry, 8) as usize) * sector,
+                len:    le_u32(entry, 12) as usize * sector,
+            });
+            *number += 1;
+        }
+        match entries().find(|entry| EXTENDED_TYPES.contains(&entry[4])) {
+            Some(link) => ebr_lba = first + le_u32(link, 8) as usize,
+            None => break,
+        }
+    }
+    Ok(partitions)
+}
+
+/// Lists partitions of the GPT or, lacking one, those of the MBR including logical partitions
+/// inside extended ones, numbered the way the kernel does.
+pub fn partitions(dev: &fs::File, sector: usize) -> Result<Vec<Partition>> {
+    if let Some(table) = read_gpt(dev, sector)? {
+        let entry_size = le_u32(&table.header, HDR_ENTRY_SIZE) as usize;
+        return Ok(table
+            .entries
+            .chunks_exact(entry_size)
+            .enumerate()
+            .filter(|(_, entry)| entry[..16].iter().any(|&b| b != 0))
+            .map(|(n, entry)| {
+                let first = le_u64(entry, 32) as usize;
+                let last = le_u64(entry, 40) as usize;
+                Partition {
+                    number: n + 1,
+                    start:  first * sector,
+                    len:    (last + 1).saturating_sub(first) * sector,
+                }
+            })
+            .collect());
+    }
+
+    let mbr = read_at(dev, 0, sector)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        debug!("No MBR signature found");
+        return Ok(Vec::new());
+    }
+    let mut partitions = Vec::new();
+    let mut extended = Vec::new();
+    for (n, entry) in mbr[446..510].chunks_exact(16).enumerate() {
+        let start = le_u32(entry, 8) as usize;
+        let len = le_u32(entry, 12) as usize * sector;
+        match entry[4] {
+            0 => (),
+            PROTECTIVE_TYPE => return Err(eyre!("Protective MBR without valid GPT")),
+            kind if EXTENDED_TYPES.contains(&kind) => {
+                // Kernel exposes only the head of an extended partition, room for a boot loader
+                partitions.push(Partition {
+                    number: n + 1,
+                    start:  start * sector,
+                    len:    len.min(sector.max(1024)),
+                });
+                extended.push(start);
+            },
+            _ => partitions.push(Partition {
+                number: n + 1,
+                start: start * sector,
+                len,
+            }),
+        }
+    }
+
+    let mut number = 5;
+    for first in extended {
+        partitions.extend(logical_partitions(dev, sector, first, &mut number)?);
+    }
+    Ok(partitions)
+}
+
+impl Relocation {
+    /// Parses the primary GPT of the already written image and prepares patches relocating
+    /// the backup header and partition entries to the end of the media. Returns `None` if
+    /// there is no GPT or the backup is already in place.
+    pub fn plan(
+        dev: &fs::File,
+        sector: usize,
+        image_len: usize,
+        dev_size: usize,
+    ) -> Result<Option<Relocation>> {
+        let Some(Table {
+            mut header,
+            header_size,
+            mut entries,
+        }) = read_gpt(dev, sector)?
+        else {
+            return Ok(None);
+        };
+
+        let last_lba = (dev_size / sector - 1) as u64;
+        let old_alternate = le_u64(&header, HDR_ALTERNATE_LBA);
+        if old_alternate == last_lba {
+            debug!("Backup GPT already at the end of media");
+            return Ok(None);
+        }
+
+        let entries_sectors = entries.len().div_ceil(sector);
+        entries.resize(entries_sectors * sector, 0);
+
+        let new_entries_lba = last_lba - entries_sectors as u64;
+        let new_last_usable = new_entries_lba - 1;
+        if new_last_usable < le_u64(&header, HDR_LAST_USABLE) {
+            return Err(eyre!("Media too small to hold relocated GPT"));
+        }
+
+        put_u64(&mut header, HDR_ALTERNATE_LBA, last_lba);
+        put_u64(&mut header, HDR_LAST_USABLE, new_last_usable);
+        let crc = header_crc(&header, header_size);
+        put_u32(&mut header, HDR_CRC, crc);
+
+        let mut backup = header.clone();
+        put_u64(&mut backup, HDR_MY_LBA, last_lba);
+        put_u64(&mut backup, HDR_ALTERNATE_LBA, 1);
+        put_u64(&mut backup, HDR_ENTRIES_LBA, new_entries_lba);
+        let crc = header_crc(&backup, header_size);
+        put_u32(&mut backup, HDR_CRC, crc);
+
+        let mut patches = Vec::new();
+
+        let mut mbr = read_at(dev, 0, sector)?;
+        let mut mbr_changed = false;
+        for entry in mbr[446..510].chunks_exact_mut(16) {
+            if entry[4] == PROTECTIVE_TYPE && le_u32(entry, 8) == 1 {
+                put_u32(entry, 12, last_lba.min(u32::MAX as u64) as u32);
+                mbr_changed = true;
+            }
+        }
+        if mbr_changed {
+            patches.push(Patch {
+                offset: 0,
+                data:   mbr,
+            });
+        }
+
+        patches.push(Patch {
+            offset: sector,
+            data:   header,
+        });
+
+        // Stale backup header left in the middle of the media would confuse partitioning tools
+        let old_backup = old_alternate as usize * sector;
+        if old_backup + sector <= image_len && old_backup > sector {
+            patches.push(Patch {
+                offset: old_backup,
+                data:   vec![0u8; sector],
+            });
+        }
+
+        patches.push(Patch {
+            offset: new_entries_lba as usize * sector,
+            data:   entries,
+        });
+        patches.push(Patch {
+            offset: last_lba as usize * sector,
+            data:   backup,
+        });
+
+        Ok(Some(Relocation { patches }))
+    }
+
+    /// Applies patches to a copy of data read from `offset`, so it matches the media after
+    /// relocation.
+    pub fn overlay(&self, offset: usize, buf: &mut [u8]) {
+        let end = offset + buf.len();
+        for patch in &self.patches {
+            let patch_end = patch.offset + patch.data.len();
+            if patch_end <= offset || patch.offset >= end {
+                continue;
+            }
+            let from = patch.offset.max(offset);
+            let to = patch_end.min(end);
+            buf[from - offset..to - offset]
+                .copy_from_slice(&patch.data[from - patch.offset..to - patch.offset]);
+        }
+    }
+
+    /// Writes all the patches. Cancelling is left to the caller, as a partial set leaves the
+    /// media with a broken GPT.
+    pub fn apply(&self, dev: &fs::File) -> Result<()> {
+        let mut buf = AlignedBuffer::new();
+        let aligned_buf = buf.get_aligned_buf();
+        for patch in &self.patches {
+            let len = patch.data.len();
+            aligned_buf[..len].copy_from_slice(&patch.data);
+            dev.write_all_at(&aligned_buf[..len], patch.offset as u64)
+                .with_context(|| format!("failed to write GPT at offset {}", patch.offset))?;
+        }
+        dev.sync_all().context("failed to flush GPT")?;
+        Ok(())
+    }
+
+    /// Reads back all patched sectors, including the ones not covered by the image checksum.
+    pub fn verify(&self, dev: &fs::File) -> Result<bool> {
+        for patch in &self.patches {
+            let data = read_at(dev, patch.offset, patch.data.len())?;
+            // Patches may overlap, so compare against the combined result
+            let mut expected = data.clone();
+            self.overlay(patch.offset, &mut expected);
+            if data != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: usize = 512;
+    const IMAGE_SECTORS: usize = 100;
+    const DEV_SECTORS: usize = 200;
+
+    /// Image with a protective MBR, a GPT holding one partition and its backup right after
+    /// the usable space, written to a file of `DEV_SECTORS` sectors.
+    fn image() -> fs::File {
+        let last = IMAGE_SECTORS as u64 - 1;
+        let mut image = vec![0u8; IMAGE_SECTORS * SECTOR];
+
+        let mbr = &mut image[..SECTOR];
+        mbr[446 + 4] = PROTECTIVE_TYPE;
+        put_u32(&mut mbr[446..], 8, 1);
+        put_u32(&mut mbr[446..], 12, last as u32);
+        mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+        let mut entries = vec![0u8; 128 * 128];
+        entries[..16].fill(0xAB);
+        put_u64(&mut entries, 32, 34);
+        put_u64(&mut entries, 40, 60);
+
+        let mut header = vec![0u8; SECTOR];
+        header[..8].copy_from_slice(SIGNATURE);
+        put_u32(&mut header, 8, 0x0001_0000);
+        put_u32(&mut header, 12, MIN_HEADER_SIZE as u32);
+        put_u64(&mut header, HDR_MY_LBA, 1);
+        put_u64(&mut header, HDR_ALTERNATE_LBA, last);
+        put_u64(&mut header, 40, 34);
+        put_u64(&mut header, HDR_LAST_USABLE, last - 33);
+        put_u64(&mut header, HDR_ENTRIES_LBA, 2);
+        put_u32(&mut header, HDR_ENTRIES_NUM, 128);
+        put_u32(&mut header, HDR_ENTRY_SIZE, 128);
+        put_u32(&mut header, HDR_ENTRIES_CRC, crc32fast::hash(&entries));
+        let crc = header_crc(&header, MIN_HEADER_SIZE);
+        put_u32(&mut header, HDR_CRC, crc);
+
+        let mut backup = header.clone();
+        put_u64(&mut backup, HDR_MY_LBA, last);
+        put_u64(&mut backup, HDR_ALTERNATE_LBA, 1);
+        put_u64(&mut backup, HDR_ENTRIES_LBA, last - 32);
+        let crc = header_crc(&backup, MIN_HEADER_SIZE);
+        put_u32(&mut backup, HDR_CRC, crc);
+
+        image[SECTOR..2 * SECTOR].copy_from_slice(&header);
+        image[2 * SECTOR..34 * SECTOR].copy_from_slice(&entries);
+        image[(IMAGE_SECTORS - 33) * SECTOR..(IMAGE_SECTORS - 1) * SECTOR]
+            .copy_from_slice(&entries);
+        image[(IMAGE_SECTORS - 1) * SECTOR..].copy_from_slice(&backup);
+
+        let dev = tempfile::tempfile().unwrap();
+        dev.write_all_at(&image, 0).unwrap();
+        dev.set_len((DEV_SECTORS * SECTOR) as u64).unwrap();
+        dev
+    }
+
+    #[test]
+    fn relocates_backup_to_end_of_media() {
+        let dev = image();
+        let image_len = IMAGE_SECTORS * SECTOR;
+        let relocation = Relocation::plan(&dev, SECTOR, image_len, DEV_SECTORS * SECTOR)
+            .unwrap()
+            .unwrap();
+
+        // Overlay over the untouched image gives what the media holds after applying
+        let mut expected = vec![0u8; DEV_SECTORS * SECTOR];
+        dev.read_exact_at(&mut expected, 0).unwrap();
+        relocation.overlay(0, &mut expected);
+        assert!(!relocation.verify(&dev).unwrap());
+
+        relocation.apply(&dev).unwrap();
+        assert!(relocation.verify(&dev).unwrap());
+        let mut written = vec![0u8; DEV_SECTORS * SECTOR];
+        dev.read_exact_at(&mut written, 0).unwrap();
+        assert_eq!(written, expected);
+
+        let last = DEV_SECTORS as u64 - 1;
+        let primary = read_at(&dev, SECTOR, SECTOR).unwrap();
+        assert_eq!(le_u64(&primary, HDR_ALTERNATE_LBA), last);
+        assert_eq!(le_u64(&primary, HDR_LAST_USABLE), last - 33);
+
+        let backup = &written[last as usize * SECTOR..];
+        assert_eq!(&backup[..8], SIGNATURE);
+        assert_eq!(le_u64(backup, HDR_MY_LBA), last);
+        assert_eq!(le_u64(backup, HDR_ALTERNATE_LBA), 1);
+        assert_eq!(le_u64(backup, HDR_ENTRIES_LBA), last - 32);
+        assert_eq!(header_crc(backup, MIN_HEADER_SIZE), le_u32(backup, HDR_CRC));
+        assert_eq!(
+            &written[(last as usize - 32) * SECTOR..(last as usize - 31) * SECTOR],
+            &written[2 * SECTOR..3 * SECTOR]
+        );
+
+        let old_backup = (IMAGE_SECTORS - 1) * SECTOR;
+        assert!(
+            written[old_backup..old_backup + SECTOR]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        assert_eq!(le_u32(&written[446..], 12), last as u32);
+
+        // Once in place, there's nothing left to do
+        assert!(
+            Relocation::plan(&dev, SECTOR, image_len, DEV_SECTORS * SECTOR)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn lists_gpt_partitions() {
+        let dev = image();
+        assert_eq!(
+            partitions(&dev, SECTOR).unwrap(),
+            [Partition {
+                number: 1,
+                start:  34 * SECTOR,
+                len:    27 * SECTOR,
+            }]
+        );
+    }
+
+    #[test]
+    fn lists_logical_partitions() {
+        fn record(table: &mut [u8], entries: &[(u8, u32, u32)]) {
+            for (entry, &(kind, start, len)) in table[446..510].chunks_exact_mut(16).zip(entries) {
+                entry[4] = kind;
+                put_u32(entry, 8, start);
+                put_u32(entry, 12, len);
+            }
+            table[510..512].copy_from_slice(&[0x55, 0xAA]);
+        }
+
+        // Primary and extended partition, the latter chaining two logical ones
+        let mut disk = vec![0u8; 64 * SECTOR];
+        record(&mut disk[..SECTOR], &[(0x83, 1, 9), (0x05, 10, 40)]);
+        record(
+            &mut disk[10 * SECTOR..11 * SECTOR],
+            &[(0x83, 1, 9), (0x05, 20, 20)],
+        );
+        record(&mut disk[30 * SECTOR..31 * SECTOR], &[(0x83, 2, 5)]);
+        let dev = tempfile::tempfile().unwrap();
+        dev.write_all_at(&disk, 0).unwrap();
+
+        let partition = |number, start: usize, len: usize| Partition {
+            number,
+            start: start * SECTOR,
+            len: len * SECTOR,
+        };
+        assert_eq!(
+            partitions(&dev, SECTOR).unwrap(),
+            [
+                partition(1, 1, 9),
+                partition(2, 10, 2),
+                partition(5, 11, 9),
+                partition(6, 32, 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_header() {
+        let dev = image();
+        dev.write_all_at(&[0xFF], SECTOR as u64 + HDR_LAST_USABLE as u64)
+            .unwrap();
+        assert!(
+            Relocation::plan(&dev, SECTOR, IMAGE_SECTORS * SECTOR, DEV_SECTORS * SECTOR).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_media() {
+        // Header signature in place, but the media ends right after it
+        let dev = tempfile::tempfile().unwrap();
+        dev.write_all_at(SIGNATURE, SECTOR as u64).unwrap();
+        assert!(
+            Relocation::plan(&dev, SECTOR, IMAGE_SECTORS * SECTOR, DEV_SECTORS * SECTOR).is_err()
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    cli,
+    usb::{self, Device, Protected},
+};
+use color_eyre::eyre::Result;
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{collections::HashSet, path, thread, time::Duration};
+
+const POLL: Duration = Duration::from_secs(1);
+/// Time for sticks plugged in together to all show up, and for the kernel to read their
+/// partition tables.
+const SETTLE: Duration = Duration::from_secs(2);
+
+/// Tells if the device is what the operator asked for with `--vendor`, `--model` and size
+/// limits.
+fn matches(device: &Device) -> bool {
+    let args = cli::args();
+    let contains = |field: &str, wanted: &Option<String>| {
+        wanted
+            .as_ref()
+            .is_none_or(|wanted| field.to_lowercase().contains(&wanted.to_lowercase()))
+    };
+
+    contains(&device.vendor, &args.vendor)
+        && contains(&device.model, &args.model)
+        && args.min_size.is_none_or(|min| device.size >= min)
+        && args.max_size.is_none_or(|max| device.size <= max)
+}
+
+/// Device node with the serial, so a stick swapped for another between polls still counts
+/// as new.
+fn identities(devices: &[Device]) -> HashSet<(path::PathBuf, String)> {
+    devices
+        .iter()
+        .map(|device| (device.dev.clone(), device.serial.clone()))
+        .collect()
+}
+
+/// Watches for removable media being plugged in, by polling sysfs.
+pub struct Watcher {
+    known: HashSet<(path::PathBuf, String)>,
+}
+
+impl Watcher {
+    /// Devices already present are known, only those plugged in later count.
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            known: identities(&usb::scan()?),
+        })
+    }
+
+    /// Blocks until devices matching the filter get plugged in.
+    pub fn wait(&mut self) -> Result<Vec<Device>> {
+        loop {
+            thread::sleep(POLL);
+            let devices = usb::scan()?;
+            let current = identities(&devices);
+            if current.is_subset(&self.known) {
+                // Forget the pulled ones, their nodes get reused
+                self.known = current;
+                continue;
+            }
+
+            thread::sleep(SETTLE);
+            let devices = usb::scan()?;
+            let protected = Protected::load()?;
+            let mut found = Vec::new();
+            for device in &devices {
+                if self
+                    .known
+                    .contains(&(device.dev.clone(), device.serial.clone()))
+                {
+                    continue;
+                }
+                if !matches(device) {
+                    info!("Ignoring {device}, not matching the filter");
+                    continue;
+                }
+                match protected.check(device, false) {
+                    Ok(()) => found.push(device.clone()),
+                    Err(e) => warn!("Skipped {dev:?}: {e}", dev = device.dev),
+                }
+            }
+            self.known = identities(&devices);
+
+            if !found.is_empty() {
+                return Ok(found);
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::tools::eyre_unroll;
+use color_eyre::eyre::{Context, Result};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    collections::BTreeMap,
+    fs, io, path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+/// Progress of writes, kept across runs so interrupted ones can be resumed.
+const JOURNAL: &str = "/var/lib/image_writer_rs/journal.yaml";
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+struct Entries {
+    /// Writes in progress by device serial number.
+    devices: BTreeMap<String, Entry>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Entry {
+    image:  String,
+    sha256: String,
+    /// Image data up to here was flushed to the device.
+    offset: usize,
+}
+
+/// Journal of the image being written. Devices lacking a serial number can't be told apart,
+/// so they're never journaled.
+pub struct Journal {
+    path:     path::PathBuf,
+    entries:  Mutex<Entries>,
+    image:    String,
+    sha256:   String,
+    /// Set once saving failed, so the failure is reported just once.
+    disabled: AtomicBool,
+}
+
+impl Journal {
+    pub fn load(image: &path::Path, sha256: [u8; 32]) -> Result<Self> {
+        Self::load_from(path::Path::new(JOURNAL), image, sha256)
+    }
+
+    fn load_from(path: &path::Path, image: &path::Path, sha256: [u8; 32]) -> Result<Self> {
+        let entries = match fs::read_to_string(path) {
+            Ok(data) => serde_yaml::from_str(&data).context("failed to parse journal")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Entries::default(),
+            Err(e) => return Err(e).context("failed to read journal"),
+        };
+
+        Ok(Self {
+            path:     path.to_path_buf(),
+            entries:  Mutex::new(entries),
+            image:    image.to_string_lossy().to_string(),
+            sha256:   hex::encode(sha256),
+            disabled: AtomicBool::new(false),
+        })
+    }
+
+    fn save(&self, entries: &Entries) -> Result<()> {
+        let file = &self.path;
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).context("failed to create journal directory")?;
+        }
+        let payload = serde_yaml::to_string(entries)?;
+        // Replaced at once, so being interrupted never leaves a truncated journal behind
+        let temp = file.with_extension("tmp");
+        fs::write(&temp, payload).context("failed to write journal")?;
+        fs::rename(&temp, file).context("failed to replace journal")?;
+        Ok(())
+    }
+
+    /// Applies the change and saves the journal, if the change did anything. Gives up on the
+    /// journal after the first failure.
+    fn update(&self, serial: &str, change: impl FnOnce(&mut Entries) -> bool) {
+        if serial.is_empty() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if self.disabled.load(Ordering::Relaxed) || !change(&mut entries) {
+            return;
+        }
+        if let Err(e) = self.save(&entries) {
+            warn!(
+                "Failed to update journal, interrupted writes won't be resumable: {e}",
+                e = eyre_unroll(&e)
+            );
+            self.disabled.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Offset to resume writing the device at, if it was interrupted writing this image.
+    pub fn offset(&self, serial: &str) -> Option<usize> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .devices
+            .get(serial)
+            .filter(|entry| !serial.is_empty() && entry.sha256 == self.sha256)
+            .map(|entry| entry.offset)
+            .filter(|&offset| offset > 0)
+    }
+
+    /// Records image data up to `offset` as flushed to the device.
+    pub fn record(&self, serial: &str, offset: usize) {
+        self.update(serial, |entries| {
+            let entry = Entry {
+                image: self.image.clone(),
+                sha256: self.sha256.clone(),
+                offset,
+            };
+            entries.devices.insert(serial.to_string(), entry);
+            true
+        });
+        trace!("Journaled {serial}: {offset}");
+    }
+
+    /// Forgets the device once written in full.
+    pub fn finish(&self, serial: &str) {
+        self.update(serial, |entries| entries.devices.remove(serial).is_some());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUM: [u8; 32] = [7; 32];
+
+    fn load(path: &path::Path, sha256: [u8; 32]) -> Journal {
+        Journal::load_from(path, path::Path::new("image.img"), sha256).unwrap()
+    }
+
+    #[test]
+    fn keeps_progress_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal").join("journal.yaml");
+
+        let journal = load(&path, SUM);
+        assert_eq!(journal.offset("ABC"), None);
+        journal.record("ABC", 4096);
+        journal.record("DEF", 8192);
+        // Devices without serial number are never journaled
+        journal.record("", 512);
+
+        let journal = load(&path, SUM);
+        assert_eq!(journal.offset("ABC"), Some(4096));
+        assert_eq!(journal.offset("DEF"), Some(8192));
+        assert_eq!(journal.offset(""), None);
+        journal.finish("ABC");
+
+        let journal = load(&path, SUM);
+        assert_eq!(journal.offset("ABC"), None);
+        assert_eq!(journal.offset("DEF"), Some(8192));
+        // Progress of another image is of no use
+        assert_eq!(load(&path, [8; 32]).offset("DEF"), None);
+    }
+
+    #[test]
+    fn rejects_damaged_journal() {
+        let file = crate::tools::temp_file(b"devices: [");
+        assert!(Journal::load_from(file.path(), path::Path::new("image.img"), SUM).is_err());
+    }
+}
@@ -1,19 +1,43 @@
+mod blkdev;
+mod bmap;
+mod cli;
 mod database;
+mod error;
+mod gpt;
+mod hotplug;
+mod journal;
+mod mounts;
+mod qcow2;
 mod reader;
+#[cfg(feature = "parallel")]
+mod seekable;
+mod simg;
+mod tar;
 mod tools;
 mod usb;
-
-use crate::{reader::*, tools::*, usb::*};
+mod vhd;
+mod vhdx;
+mod vmdk;
+mod writer;
+mod zip;
+
+use crate::{
+    error::{Error, ResultExt},
+    reader::*,
+    tools::*,
+    usb::*,
+};
 use color_eyre::eyre::{Context, Result, eyre};
+use dialoguer::{Confirm, Select, theme::ColorfulTheme};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use sha2::Digest;
 use std::{
-    fs,
-    io::{Read, Seek, Write},
-    os::unix::fs::OpenOptionsExt,
+    io::Read,
     path,
-    sync::mpsc,
+    process::ExitCode,
+    sync::{Arc, mpsc},
+    thread,
 };
 
 const KB: f64 = 1024.0;
@@ -21,74 +45,248 @@ const MB: f64 = KB * 1024.0;
 const GB: f64 = MB * 1024.0;
 
 const BUFFERS: usize = 4;
+/// Unit disk images come in, whatever the sector size of the target. Writers merge a tail not
+/// filling a larger sector with what the device holds.
+const IMAGE_SECTOR: usize = 512;
 
 enum ReaderResult {
-    Ready,
+    /// Image opened, data is sent from this offset on.
+    Ready(usize),
     Done,
     Error,
     Block(AlignedBuffer),
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+/// Streams given ranges of the image to the writer, skipping the holes between them. Checks
+/// ranges carrying a checksum and, if asked to, returns SHA256 of all the data sent and marks
+/// blocks holding only zeroes.
+fn read_image(
+    mut decompressor: Box<dyn Stream>,
+    ranges: &[bmap::Range],
+    checksum_type: Option<bmap::ChecksumType>,
+    calc_sum: bool,
+    find_zeroes: bool,
+    wrrx: &mpsc::Receiver<Arc<AlignedBuffer>>,
+    rdtx: &mpsc::SyncSender<ReaderResult>,
+) -> Result<Option<[u8; 32]>> {
+    let mut mapped_sum = calc_sum.then(sha2::Sha256::new);
+    let mut pos = 0;
+
+    for range in ranges {
+        decompressor
+            .skip(range.start - pos)
+            .context("failed to skip unmapped data")?;
+
+        let mut range_sum = checksum_type
+            .filter(|_| range.checksum.is_some())
+            .map(|checksum_type| checksum_type.hasher());
+
+        let mut offset = range.start;
+        while offset < range.end {
+            if cancelled() {
+                return Err(eyre!("interrupted"));
+            }
+            let mut buf = loop {
+                if let Some(buf) = Arc::into_inner(wrrx.recv()?) {
+                    break buf;
+                }
+            };
+            let read_block_size = (range.end - offset).clamp(0, buf.size());
+            let aligned_buf = buf.get_aligned_buf();
 
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
+            decompressor
+                .read_exact(&mut aligned_buf[..read_block_size])
+                .context("failed to read image")?;
 
-    let source_file = match std::env::args().nth(1) {
-        Some(v) => path::PathBuf::from(v),
-        None => {
-            error!("No disk image path given, aborting");
-            return Ok(());
-        },
-    };
+            if let Some(sum) = mapped_sum.as_mut() {
+                sum.update(&aligned_buf[..read_block_size]);
+            }
+            if let Some(sum) = range_sum.as_mut() {
+                sum.update(&aligned_buf[..read_block_size]);
+            }
 
-    let ext = match source_file.extension() {
-        None => {
-            error!("Unknown image type. Quitting");
-            return Ok(());
-        },
-        Some(ext) => ext.to_string_lossy().to_ascii_uppercase(),
-    };
+            let zero = find_zeroes && aligned_buf[..read_block_size].iter().all(|&b| b == 0);
 
-    let device = match detect_pendrives() {
-        Ok(device) => device,
-        Err(e) => {
-            error!("Detecting pendrives failed: {}", eyre_unroll(e));
-            return Ok(());
-        },
-    };
+            buf.used = read_block_size;
+            buf.offset = offset;
+            buf.zero = zero;
+            rdtx.send(ReaderResult::Block(buf))
+                .map_err(|_| eyre!("writer stopped"))?;
+            offset += read_block_size;
+        }
 
-    let reader = match by_ext(&ext) {
-        Ok(reader) => reader,
+        if let (Some(expected), Some(sum)) = (&range.checksum, range_sum)
+            && *sum.finalize() != expected[..]
+        {
+            return Err(eyre!(
+                "image data at {start}..{end} doesn't match block map checksum",
+                start = range.start,
+                end = range.end
+            ));
+        }
+        pos = range.end;
+    }
+
+    Ok(mapped_sum.map(|sum| sum.finalize().into()))
+}
+
+/// Parts of the ranges from `start` on. Ranges cut short lose their checksum, which can't be
+/// checked anymore.
+fn ranges_from(ranges: &[bmap::Range], start: usize) -> Vec<bmap::Range> {
+    ranges
+        .iter()
+        .filter(|range| range.end > start)
+        .map(|range| bmap::Range {
+            start:    range.start.max(start),
+            end:      range.end,
+            checksum: range.checksum.clone().filter(|_| range.start >= start),
+        })
+        .collect()
+}
+
+/// Makes sure the image starts with an MBR boot signature, asking what to do if it doesn't.
+fn check_bootable(reader: &dyn Decompressor, source_file: &path::Path) -> Result<(), Error> {
+    let mut boot = [0u8; 512];
+    reader
+        .open_reader(source_file)
+        .and_then(|mut image| {
+            image
+                .read_exact(&mut boot)
+                .context("failed to read boot sector")
+        })
+        .wrap_err("Failed to analyze file")
+        .fail_as(Error::BadImage)?;
+    if boot[510] == 0x55 && boot[511] == 0xAA {
+        return Ok(());
+    }
+
+    warn!("This doesn't look like a hybrid image (lacks MBR signature)");
+    let args = cli::args();
+    if args.accept_non_bootable {
+        info!("Writing anyway, as requested");
+        return Ok(());
+    }
+    if args.non_interactive {
+        return Err(Error::BadImage(eyre!(
+            "Image lacks MBR signature, use --accept-non-bootable to write it anyway"
+        )));
+    }
+
+    let selection = Select::with_theme(&ColorfulTheme::default())
+        .default(0)
+        .with_prompt("What to do:")
+        .items(&["Abort", "Continue"])
+        .interact()
+        .context("failed to read answer")?;
+    if selection == 0 {
+        return Err(Error::Aborted);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e @ Error::Usage(_)) => {
+            // Logger is not set up yet
+            eprintln!("Invalid arguments: {e}");
+            eprintln!("For more information, try '--help'.");
+            ExitCode::from(e.code())
+        },
         Err(e) => {
-            error!("Detecting decompressor failed: {}", eyre_unroll(e));
-            return Ok(());
+            error!("{e}");
+            ExitCode::from(e.code())
         },
+    }
+}
+
+/// Image ready to be written, with everything known about it up front.
+struct Source {
+    path:          path::PathBuf,
+    reader:        Box<dyn Decompressor>,
+    len:           usize,
+    ranges:        Vec<bmap::Range>,
+    checksum_type: Option<bmap::ChecksumType>,
+    sum:           Option<[u8; 32]>,
+}
+
+/// Detects the image format, then finds its length and checksum from the block map, the
+/// checksum database or by reading it through.
+fn prepare_source(source_file: &path::Path) -> Result<Source, Error> {
+    let args = cli::args();
+
+    let reader = match &args.format {
+        Some(format) => by_ext(format),
+        None => detect(source_file),
     };
+    let mut reader = reader
+        .wrap_err("Detecting decompressor failed")
+        .fail_as(Error::BadImage)?;
+
+    reader
+        .prepare(source_file)
+        .wrap_err("Failed to open archive")
+        .fail_as(Error::BadImage)?;
+
+    check_bootable(reader.as_ref(), source_file)?;
 
     let source_dir = source_file.parent().unwrap();
-    let source_name = source_file
+    let mut source_name = source_file
         .file_name()
-        .ok_or_else(|| eyre!("Malformed path"))?;
+        .ok_or_else(|| eyre!("Malformed path"))?
+        .to_os_string();
+    // Each member of an archive needs its own checksum
+    if let Some(member) = reader.get_member() {
+        source_name.push("/");
+        source_name.push(member);
+    }
 
-    let mut db = match database::Database::load(source_dir) {
-        Ok(db) => db,
-        Err(err) => {
-            warn!("Failed to load checksum database: {err}");
-            database::Database::new(source_dir)
+    let block_map = match bmap::BlockMap::find(source_file) {
+        None => reader
+            .block_map(source_file)
+            .wrap_err("Failed to analyze file")
+            .fail_as(Error::BadImage)?,
+        Some(bmap_file) => match bmap::BlockMap::load(&bmap_file) {
+            Ok(block_map) => {
+                info!("Using block map {bmap_file:?}");
+                Some(block_map)
+            },
+            Err(e) => {
+                warn!("Ignoring block map {bmap_file:?}: {}", eyre_unroll(&e));
+                None
+            },
         },
     };
 
-    let (source_sum, len) = match db.get(&source_name) {
+    let (source_sum, len) = match &block_map {
+        Some(block_map) => (None, block_map.image_size),
         None => {
-            info!(
-                "Calculating length and checksum of {source_file:?}, {comp}.",
-                comp = reader.get_name()
-            );
-            match reader.get_size_sum(&source_file) {
-                Ok((sum, size)) => {
+            let mut db = match database::Database::load(source_dir) {
+                Ok(db) => db,
+                Err(err) => {
+                    warn!("Failed to load checksum database: {err}");
+                    database::Database::new(source_dir)
+                },
+            };
+
+            match (db.get(&source_name), reader.get_size()) {
+                (None, Some(size)) => {
+                    info!(
+                        "Length of {source_file:?}, {comp} known up front, checksum is calculated \
+                         while writing.",
+                        comp = reader.get_name()
+                    );
+                    (None, size)
+                },
+                (None, None) => {
+                    info!(
+                        "Calculating length and checksum of {source_file:?}, {comp}.",
+                        comp = reader.get_name()
+                    );
+                    let (sum, size) = reader
+                        .get_size_sum(source_file)
+                        .wrap_err("Failed to analyze file")
+                        .fail_as(Error::BadImage)?;
                     db.put(&source_name, sum, size);
                     match db.save() {
                         Ok(_) => {
@@ -98,226 +296,652 @@ fn main() -> Result<()> {
                             warn!("Failed to update checksum database: {err}");
                         },
                     }
-                    (sum, size)
+                    (Some(sum), size)
                 },
-                Err(e) => {
-                    error!("Failed to analyze file: {}", eyre_unroll(e));
-                    return Ok(());
+                (Some(val), _) => {
+                    info!(
+                        "Loaded checksum for {source_file:?}, {comp} from database.",
+                        comp = reader.get_name()
+                    );
+
+                    (Some(val.0), val.1)
                 },
             }
         },
-        Some(val) => {
-            info!(
-                "Loaded checksum for {source_file:?}, {comp} from database.",
-                comp = reader.get_name()
-            );
-
-            val
-        },
     };
 
-    if len % 512 != 0 {
-        error!("Image length not multiple of sector size");
-        return Ok(());
+    if !len.is_multiple_of(IMAGE_SECTOR) {
+        return Err(Error::BadImage(eyre!(
+            "Image length not multiple of sector size"
+        )));
     }
 
-    if len > device.size {
-        error!("Image won't fit on media");
-        return Ok(());
+    if let Some(source_sum) = source_sum {
+        info!(
+            "Decompressed file SHA256: {sha}",
+            sha = source_sum
+                .iter()
+                .map(|byte| format!("{byte:02X}"))
+                .collect::<Vec<_>>()
+                .join("")
+        );
     }
 
-    info!(
-        "Decompressed file SHA256: {sha}",
-        sha = source_sum
-            .iter()
-            .map(|byte| format!("{byte:02X}"))
-            .collect::<Vec<_>>()
-            .join("")
-    );
-
-    let size_txt = match len as f64 {
-        mb if mb < GB => format!("{data:.2}MiB", data = mb / MB),
-        gb => format!("{data:.2}GiB", data = gb / GB),
+    let (ranges, checksum_type) = match block_map {
+        Some(block_map) => (block_map.ranges, Some(block_map.checksum_type)),
+        None => (
+            vec![bmap::Range {
+                start:    0,
+                end:      len,
+                checksum: None,
+            }],
+            None,
+        ),
     };
 
-    countdown(10, &device.model);
+    Ok(Source {
+        path: source_file.to_path_buf(),
+        reader,
+        len,
+        ranges,
+        checksum_type,
+        sum: source_sum,
+    })
+}
 
-    info!(
-        "Copying {size_txt} from {source_file:?} to {dev:?}",
-        dev = device.dev,
-    );
+/// Lists whatever uses the devices, failing for what can't or shouldn't be released.
+fn find_busy(devices: &[Device]) -> Result<Vec<mounts::Busy>, Error> {
+    let mut busy = Vec::new();
+    for device in devices {
+        let found = mounts::find(device)
+            .with_context(|| format!("Failed to check if {dev:?} is in use", dev = device.dev))
+            .fail_as(Error::NoDevice)?;
+        for user in &found {
+            warn!("{user}");
+        }
+        busy.extend(found);
+    }
+    if let Some(held) = busy.iter().find(|user| !user.releasable()) {
+        return Err(Error::NoDevice(eyre!("Device in use, {held}")));
+    }
+    if !busy.is_empty() && !cli::args().unmount {
+        return Err(Error::NoDevice(eyre!(
+            "Device in use, unmount it first or use --unmount"
+        )));
+    }
+    Ok(busy)
+}
 
-    let (wrtx, wrrx) = mpsc::sync_channel(BUFFERS);
-    let (rdtx, rdrx) = mpsc::sync_channel(BUFFERS);
+fn check_fit(devices: &[Device], len: usize) -> Result<(), Error> {
+    match devices.iter().find(|device| len > device.size) {
+        Some(device) => Err(Error::TooLarge {
+            image:  len,
+            device: device.size,
+        }),
+        None => Ok(()),
+    }
+}
 
-    for _ in 0..BUFFERS {
-        wrtx.send(AlignedBuffer::new())?;
+/// Asks whether to continue the interrupted write of the device, unless decided by options.
+fn confirm_resume(
+    multi: &indicatif::MultiProgress,
+    device: &Device,
+    offset: usize,
+) -> Result<bool, Error> {
+    let args = cli::args();
+    let written = offset as f64 / MB;
+    if args.resume {
+        return Ok(true);
+    }
+    if args.non_interactive || args.wait {
+        info!(
+            "{dev:?} was interrupted at {written:.2}MiB, writing it all again, use --resume to \
+             continue",
+            dev = device.dev
+        );
+        return Ok(false);
     }
+    Ok(multi
+        .suspend(|| {
+            Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Writing {dev:?} was interrupted at {written:.2}MiB, resume?",
+                    dev = device.dev
+                ))
+                .default(true)
+                .interact()
+        })
+        .context("failed to read answer")?)
+}
 
-    let bar = indicatif::ProgressBar::new(len as u64)
-        .with_message("Writing")
-        .with_style(indicatif::ProgressStyle::with_template(
-            "{wide_bar} {msg} {bytes_per_sec}, ETA:{eta}",
-        )?);
+/// Writes the image to all the devices at once, then verifies them. A device failing doesn't
+/// stop the others, so each one gets its own outcome. Only a broken image fails the whole run.
+fn write_source(
+    source: &Source,
+    devices: Vec<Device>,
+) -> Result<Vec<(Device, Option<Error>)>, Error> {
+    let args = cli::args();
+    let mapped: usize = source
+        .ranges
+        .iter()
+        .map(|range| range.end - range.start)
+        .sum();
+
+    let size_txt = match source.len as f64 {
+        mb if mb < GB => format!("{data:.2}MiB", data = mb / MB),
+        gb => format!("{data:.2}GiB", data = gb / GB),
+    };
+    for device in &devices {
+        info!(
+            "Copying {size_txt} from {source_file:?} to {dev:?}",
+            source_file = source.path,
+            dev = device.dev,
+        );
+    }
+    if mapped < source.len {
+        info!(
+            "Only {data:.2}MiB mapped, skipping the rest",
+            data = mapped as f64 / MB
+        );
+    }
+
+    let multi = indicatif::MultiProgress::new();
+    let mut targets = Vec::with_capacity(devices.len());
+    let mut outcomes = Vec::new();
+    for device in devices {
+        match writer::Target::open(device.clone(), &multi, mapped, args.incremental) {
+            Ok(target) => targets.push(target),
+            // Other devices may still be fine
+            Err(e) => outcomes.push((device, Some(Error::Write(e)))),
+        }
+    }
+    if targets.is_empty() {
+        return Ok(outcomes);
+    }
 
-    let mut out = match fs::OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(false)
-        .custom_flags(libc::O_DIRECT)
-        .open(&device.dev)
+    // Progress is journaled only for images known by their checksum
+    let journal = match source
+        .sum
+        .map(|sum| journal::Journal::load(&source.path, sum))
     {
-        Err(e) => {
-            error!(
-                "Failed to open output device {target:?}: {e}",
-                target = device.dev,
+        Some(Ok(journal)) => Some(journal),
+        Some(Err(e)) => {
+            warn!(
+                "Interrupted writes can't be resumed: {e}",
+                e = eyre_unroll(&e)
             );
-            return Ok(());
+            None
         },
-        Ok(out) => out,
+        None => None,
     };
+    if let Some(journal) = &journal {
+        for target in targets.iter_mut() {
+            let Some(offset) = journal.offset(&target.device.serial) else {
+                continue;
+            };
+            if confirm_resume(&multi, &target.device, offset)? {
+                info!(
+                    "Resuming {dev:?} at {data:.2}MiB",
+                    dev = target.device.dev,
+                    data = offset as f64 / MB
+                );
+                target.resume_at(offset);
+            }
+        }
+    }
 
-    let read_thread = std::thread::spawn(move || -> Result<()> {
-        let mut data_left = len;
-        let reader = match by_ext(&ext) {
-            Ok(reader) => reader,
-            Err(e) => {
+    // Blocks are shared by all the writers, the last one to give a block back releases it
+    let (wrtx, wrrx) = mpsc::channel();
+    let (rdtx, rdrx) = mpsc::sync_channel(BUFFERS);
+
+    // From now on the devices don't hold what they did, nor the whole image yet
+    let interrupts = catch_interrupts();
+
+    if args.sparse {
+        thread::scope(|scope| {
+            for target in targets.iter_mut() {
+                scope.spawn(|| target.zero());
+            }
+        });
+    }
+
+    // Shared buffers suit every device: aligned to the largest sector, sized for the device
+    // preferring the largest transfers
+    let align = targets
+        .iter()
+        .map(|target| target.device.logical_block_size)
+        .fold(PAGE_SIZE, usize::max);
+    let size = targets
+        .iter()
+        .map(|target| target.device.io_size())
+        .fold(BUF_SIZE, usize::max);
+    debug!("Using {BUFFERS} buffers of {size} bytes aligned to {align}");
+    for _ in 0..BUFFERS {
+        wrtx.send(Arc::new(AlignedBuffer::with_layout(size, align)))
+            .expect("failed to send buffer");
+    }
+
+    // Data every device holds already, from an interrupted write of the image
+    let resumed = targets
+        .iter()
+        .map(|target| target.resume)
+        .min()
+        .unwrap_or(0);
+    let written = thread::scope(|scope| -> Result<Option<[u8; 32]>, Error> {
+        let read_thread = scope.spawn(move || -> Result<Option<[u8; 32]>> {
+            let result = source
+                .reader
+                .open_stream(&source.path)
+                .and_then(|decompressor| {
+                    // Passed over only when seeking, otherwise compared with the devices
+                    let start = if decompressor.can_seek() { resumed } else { 0 };
+                    rdtx.send(ReaderResult::Ready(start))
+                        .expect("failed to send ready");
+                    read_image(
+                        decompressor,
+                        &ranges_from(&source.ranges, start),
+                        source.checksum_type,
+                        source.sum.is_none() && !args.no_verify,
+                        args.sparse,
+                        &wrrx,
+                        &rdtx,
+                    )
+                });
+
+            if result.is_err() {
                 rdtx.send(ReaderResult::Error)
                     .expect("failed to send error");
-                return Err(e);
+                return result;
+            }
+
+            rdtx.send(ReaderResult::Done).expect("failed to send done");
+            result
+        });
+
+        match rdrx.recv().context("reading thread vanished")? {
+            ReaderResult::Ready(0) => (),
+            ReaderResult::Ready(start) => {
+                info!(
+                    "Skipping {data:.2}MiB written before",
+                    data = start as f64 / MB
+                );
+                let skipped: usize = source
+                    .ranges
+                    .iter()
+                    .map(|range| range.end.min(start).saturating_sub(range.start))
+                    .sum();
+                for target in &targets {
+                    target.bar.inc(skipped as u64);
+                }
             },
-        };
-        let mut decompressor = match reader.open_reader(&source_file) {
-            Ok(d) => d,
-            Err(e) => {
-                rdtx.send(ReaderResult::Error)
-                    .expect("failed to send error");
-                return Err(e);
+            ReaderResult::Error => {
+                let result = read_thread.join().unwrap().expect_err("unexpected success");
+                return Err(result)
+                    .wrap_err("Reading thread failed")
+                    .fail_as(Error::BadImage);
             },
-        };
+            _ => {
+                _ = read_thread.join().unwrap();
+                return Err(eyre!("Unexpected read thread finish").into());
+            },
+        }
 
-        rdtx.send(ReaderResult::Ready)
-            .expect("failed to send ready");
+        let mut writers = Vec::with_capacity(targets.len());
+        for target in targets.iter_mut() {
+            let (tx, rx) = mpsc::sync_channel(BUFFERS);
+            let done = wrtx.clone();
+            let journal = journal.as_ref();
+            scope.spawn(move || target.write(rx, done, journal));
+            writers.push(tx);
+        }
 
-        while data_left > 0 {
-            let read_block_size = data_left.clamp(0, BUF_SIZE);
+        loop {
+            match rdrx.recv() {
+                Ok(ReaderResult::Block(buf)) => {
+                    let buf = Arc::new(buf);
+                    for writer in &writers {
+                        writer.send(buf.clone()).expect("writer thread died");
+                    }
+                    _ = wrtx.send(buf);
+                },
+                Ok(ReaderResult::Ready(_)) => {
+                    error!("Unexpected ready");
+                },
+                Ok(ReaderResult::Done | ReaderResult::Error) | Err(_) => break,
+            }
+        }
+        // Closing the channels lets the writers finish
+        drop(writers);
+
+        read_thread
+            .join()
+            .unwrap()
+            .wrap_err("Reading thread failed")
+            .fail_as(Error::BadImage)
+    });
+
+    if let Some(journal) = &journal {
+        let complete = written.is_ok() && !cancelled();
+        for target in &targets {
+            target.update_journal(journal, complete);
+        }
+    }
 
-            let mut buf = match wrrx.recv() {
-                Ok(buf) => buf,
-                Err(e) => {
-                    rdtx.send(ReaderResult::Error)
-                        .expect("failed to send error");
-                    return Err(e.into());
+    // Writers flushed what they had, the rest of the image is missing
+    if cancelled() {
+        for target in &targets {
+            match &target.failure {
+                None => {
+                    target.bar.abandon_with_message("Interrupted");
+                    error!(
+                        "{dev:?}: interrupted after {data:.2}MiB",
+                        dev = target.device.dev,
+                        data = target.position as f64 / MB
+                    );
                 },
-            };
+                Some(e) => error!("{dev:?}: {e}", dev = target.device.dev),
+            }
+        }
+        return Err(Error::Cancelled);
+    }
+    let mapped_sum = written?;
+    let expected_sum = source.sum.or(mapped_sum).filter(|_| !args.no_verify);
+
+    let relocations: Vec<_> = targets
+        .iter()
+        .map(|target| target.plan_relocation(source.len))
+        .collect();
+    let relocate = if relocations.iter().all(Option::is_none) || args.fix_gpt {
+        true
+    } else if args.non_interactive || args.wait {
+        info!("Leaving backup GPT in place, use --fix-gpt to move it");
+        false
+    } else {
+        let answer = multi.suspend(|| {
+            Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Move the backup GPT to the end of media?")
+                .default(false)
+                .interact()
+        });
+        // Image is written, but not verified yet
+        if cancelled() {
+            return Err(Error::Cancelled);
+        }
+        answer.context("failed to read answer")?
+    };
 
-            let aligned_buf = buf.get_aligned_buf();
+    thread::scope(|scope| {
+        for (target, relocation) in targets.iter_mut().zip(&relocations) {
+            let relocation = relocation.as_ref().filter(|_| relocate);
+            let ranges = &source.ranges;
+            scope.spawn(move || target.verify(ranges, expected_sum, relocation));
+        }
+    });
 
-            if let Err(e) = decompressor.read_exact(&mut aligned_buf[..read_block_size]) {
-                rdtx.send(ReaderResult::Error)
-                    .expect("failed to send error");
-                return Err(e.into());
-            };
+    // Image was written in full, checking it or moving the GPT got cut short
+    if cancelled() {
+        for target in &targets {
+            match &target.failure {
+                None => error!(
+                    "{dev:?}: interrupted while verifying",
+                    dev = target.device.dev
+                ),
+                Some(e) => error!("{dev:?}: {e}", dev = target.device.dev),
+            }
+        }
+        return Err(Error::Cancelled);
+    }
+    drop(interrupts);
 
-            buf.used = read_block_size;
-            rdtx.send(ReaderResult::Block(buf))
-                .expect("failed to send data");
-            data_left -= read_block_size;
+    if expected_sum.is_none() {
+        info!("Verification skipped");
+    }
+
+    for (target, relocation) in targets.into_iter().zip(relocations) {
+        if target.is_ok() && relocate && relocation.is_some() {
+            info!(
+                "Backup GPT relocated to the end of {dev:?}",
+                dev = target.device.dev
+            );
         }
-        rdtx.send(ReaderResult::Done).expect("failed to send done");
-        for _ in 0..BUFFERS {
-            wrrx.recv().expect("failed to flush buffers");
+        if target.is_ok() && args.incremental {
+            info!(
+                "{changed} bytes changed on {dev:?}, {data:.2}MiB rewritten",
+                changed = target.changed,
+                dev = target.device.dev,
+                data = target.written as f64 / MB
+            );
+        } else if target.is_ok() && target.changed > 0 {
+            warn!(
+                "{changed} bytes written before differed on {dev:?}, rewritten",
+                changed = target.changed,
+                dev = target.device.dev
+            );
         }
-        Ok(())
-    });
+        if target.is_ok() && target.skipped > 0 {
+            info!(
+                "{data:.2}MiB of zeroes skipped on {dev:?}",
+                data = target.skipped as f64 / MB,
+                dev = target.device.dev
+            );
+        }
+        if target.is_ok()
+            && let Err(e) = target.reread_partitions()
+        {
+            warn!(
+                "Failed to reload partitions of {dev:?}: {e}",
+                dev = target.device.dev,
+                e = eyre_unroll(&e)
+            );
+        }
+        if target.is_ok() && (args.eject || args.power_off) {
+            match target.eject() {
+                Ok(()) => info!("Ejected {dev:?}", dev = target.device.dev),
+                Err(e) => warn!(
+                    "Failed to eject {dev:?}: {e}",
+                    dev = target.device.dev,
+                    e = eyre_unroll(&e)
+                ),
+            }
+        }
+        outcomes.push((target.device, target.failure));
+    }
 
-    match rdrx.recv()? {
-        ReaderResult::Ready => (),
-        ReaderResult::Error => {
-            let result = read_thread.join().unwrap().expect_err("unexpected success");
-            bar.finish_and_clear();
-            error!("Reading thread failed: {}", eyre_unroll(result));
-            return Ok(());
-        },
-        _ => {
-            bar.finish_and_clear();
-            error!("Unexpected read thread finish");
-            _ = read_thread.join().unwrap();
-            return Ok(());
-        },
+    // Devices are closed by now
+    if args.power_off {
+        for (device, _) in outcomes.iter().filter(|(_, failure)| failure.is_none()) {
+            match power_off(device) {
+                Ok(()) => info!("Powered off {dev:?}", dev = device.dev),
+                Err(e) => warn!(
+                    "Failed to power off {dev:?}: {e}",
+                    dev = device.dev,
+                    e = eyre_unroll(&e)
+                ),
+            }
+        }
     }
+    Ok(outcomes)
+}
+
+/// Writes every stick plugged in, until interrupted.
+fn wait_loop(source: &Source) -> Result<(), Error> {
+    let args = cli::args();
+    let mut watcher = hotplug::Watcher::new()
+        .wrap_err("Failed to list present devices")
+        .fail_as(Error::NoDevice)?;
+    let mut sticks = 0;
+    // Each stick is checked on its own, so one left mounted doesn't hold up the others
+    let check = |device: &Device| -> Result<(), Error> {
+        let devices = std::slice::from_ref(device);
+        let busy = find_busy(devices)?;
+        check_fit(devices, source.len)?;
+        mounts::release(&busy)
+            .wrap_err("Failed to release device")
+            .fail_as(Error::NoDevice)
+    };
 
     loop {
-        match rdrx.recv()? {
-            ReaderResult::Done => break,
-            ReaderResult::Ready => {
-                error!("Unexpected ready");
-                continue;
-            },
-            ReaderResult::Error => {
-                let result = read_thread.join().unwrap().expect_err("unexpected success");
-                bar.finish_and_clear();
-                error!("Reading thread failed: {}", eyre_unroll(result));
-                return Ok(());
-            },
-            ReaderResult::Block(mut buf) => {
-                let read_block_size = buf.used;
-                let aligned_buf = buf.get_aligned_buf();
-
-                if let Err(e) = out.write_all(&aligned_buf[..read_block_size]) {
-                    bar.finish_and_clear();
-                    error!("failed to write image: {e}");
-                    return Ok(());
-                }
-                bar.inc(read_block_size as u64);
-                // After last block bails out, as thread closes
-                wrtx.send(buf).expect("failed to send back buffer");
-            },
+        info!("Waiting for devices to be plugged in");
+        let devices = watcher
+            .wait()
+            .wrap_err("Failed to watch for devices")
+            .fail_as(Error::NoDevice)?;
+
+        let mut ready = Vec::with_capacity(devices.len());
+        let mut outcomes = Vec::new();
+        for device in devices {
+            info!("Found {device}");
+            match check(&device) {
+                Ok(()) => ready.push(device),
+                Err(e) => outcomes.push((device, Some(e))),
+            }
+        }
+        if !ready.is_empty() {
+            outcomes.extend(write_source(source, ready)?);
+        }
+
+        for (device, failure) in outcomes {
+            sticks += 1;
+            match failure {
+                None if args.no_verify => info!("#{sticks} {device}: written"),
+                None => info!("#{sticks} {device}: written and verified"),
+                Some(e) => error!("#{sticks} {device}: failed: {e}"),
+            }
         }
     }
+}
 
-    if let Err(e) = read_thread.join().unwrap() {
-        error!("Reading thread failed: {}", eyre_unroll(e));
-        return Ok(());
+fn run() -> Result<(), Error> {
+    color_eyre::install()?;
+
+    cli::init().fail_as(Error::Usage)?;
+    let args = cli::args();
+
+    env_logger::builder().filter_level(args.log_level).init();
+
+    if args.wait {
+        let source = prepare_source(&args.image)?;
+        return wait_loop(&source);
     }
 
-    bar.set_position(0);
-    bar.set_message("Verifying");
+    let devices = if args.device.is_empty() {
+        detect_pendrives()
+    } else {
+        args.device
+            .iter()
+            .map(|dev| open_device(dev).with_context(|| format!("unusable device {dev:?}")))
+            .collect()
+    };
+    let mut devices = devices
+        .wrap_err("Detecting pendrives failed")
+        .fail_as(Error::NoDevice)?;
+    // Same device may be given twice, by different names
+    devices.sort_by(|dev1, dev2| dev1.dev.cmp(&dev2.dev));
+    devices.dedup_by(|dev1, dev2| dev1.dev.eq(&dev2.dev));
 
-    out.flush().context("failed to flush output file")?;
-    out.rewind().context("failed to rewind file")?;
+    let busy = find_busy(&devices)?;
 
-    let mut file_sum = sha2::Sha256::new();
-    let mut data_left = len;
+    let source = prepare_source(&args.image)?;
+    check_fit(&devices, source.len)?;
 
-    let mut read_buf = AlignedBuffer::new();
-    let read_buf = read_buf.get_aligned_buf();
+    if !args.yes && !args.non_interactive && args.countdown > 0 {
+        let models = devices
+            .iter()
+            .map(|device| device.model.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        countdown(args.countdown, &models);
+    }
 
-    while data_left > 0 {
-        let read_block_size = data_left.clamp(0, BUF_SIZE);
+    if !busy.is_empty() {
+        mounts::release(&busy)
+            .wrap_err("Failed to release devices")
+            .fail_as(Error::NoDevice)?;
+    }
 
-        if let Err(e) = out.read_exact(&mut read_buf[..read_block_size]) {
-            warn!("failed to read target for verification: {e}");
-            bar.finish_and_clear();
-            return Ok(());
+    let total = devices.len();
+    let mut failures = Vec::new();
+    for (device, failure) in write_source(&source, devices)? {
+        match failure {
+            None if total > 1 => info!("{dev:?}: done", dev = device.dev),
+            None => (),
+            Some(e) => {
+                if total > 1 {
+                    error!("{dev:?}: {e}", dev = device.dev);
+                }
+                failures.push(e);
+            },
         }
-        file_sum.update(&read_buf[..read_block_size]);
-        data_left -= read_block_size;
+    }
 
-        bar.inc(read_block_size as u64);
+    match failures.len() {
+        0 => {
+            if !args.no_verify {
+                info!("Target verification successful");
+            }
+            Ok(())
+        },
+        failed if failed < total => Err(Error::Partial { failed, total }),
+        _ => Err(failures.remove(0)),
     }
+}
 
-    bar.finish_and_clear();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    /// Zero flags of the blocks `read_image` hands out for `image`.
+    fn zero_blocks(image: &[u8], find_zeroes: bool) -> Vec<bool> {
+        let file = temp_file(image);
+        let ranges = [bmap::Range {
+            start:    0,
+            end:      image.len(),
+            checksum: None,
+        }];
+        let (wrtx, wrrx) = mpsc::channel();
+        let (rdtx, rdrx) = mpsc::sync_channel(BUFFERS);
+        for _ in 0..BUFFERS {
+            wrtx.send(Arc::new(AlignedBuffer::new())).unwrap();
+        }
 
-    let device_sum = file_sum.finalize();
-    let mut bin_checksum = [0u8; 32];
-    bin_checksum.copy_from_slice(&device_sum);
+        let stream = Box::new(BufReader::new(file.reopen().unwrap()));
+        read_image(stream, &ranges, None, false, find_zeroes, &wrrx, &rdtx).unwrap();
+        rdrx.try_iter()
+            .map(|result| {
+                let ReaderResult::Block(buf) = result else {
+                    panic!("expected a block");
+                };
+                buf.zero
+            })
+            .collect()
+    }
 
-    if source_sum.eq(&bin_checksum) {
-        info!("Target verification successful");
-    } else {
-        error!("Target verification failed");
+    #[test]
+    fn resumes_ranges_at_offset() {
+        let range = |start, end, checksum: Option<u8>| bmap::Range {
+            start,
+            end,
+            checksum: checksum.map(|sum| vec![sum]),
+        };
+        let ranges = [
+            range(0, 100, Some(1)),
+            range(200, 300, Some(2)),
+            range(400, 500, Some(3)),
+        ];
+        let resumed: Vec<_> = ranges_from(&ranges, 250)
+            .into_iter()
+            .map(|range| (range.start, range.end, range.checksum))
+            .collect();
+        assert_eq!(resumed, [(250, 300, None), (400, 500, Some(vec![3]))]);
+        assert_eq!(ranges_from(&ranges, 0).len(), 3);
     }
 
-    Ok(())
+    #[test]
+    fn marks_blocks_of_zeroes() {
+        let mut image = vec![0u8; 3 * BUF_SIZE];
+        image[BUF_SIZE + 7] = 1;
+        assert_eq!(zero_blocks(&image, true), [true, false, true]);
+        assert_eq!(zero_blocks(&image, false), [false, false, false]);
+    }
 }
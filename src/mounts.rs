@@ -0,0 +1,330 @@
+use crate::usb::Device;
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fmt, fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path,
+};
+
+/// Mount points whose devices hold the running system.
+const SYSTEM_MOUNTS: [&str; 4] = ["/", "/boot", "/boot/efi", "/home"];
+
+/// Whole disk or one of its partitions.
+struct Node {
+    dev:    path::PathBuf,
+    sys:    path::PathBuf,
+    number: String,
+}
+
+/// Line of `/proc/self/mountinfo`.
+pub struct Mount {
+    /// `major:minor` of the mounted device, anonymous for btrfs and alike.
+    pub number: String,
+    pub source: path::PathBuf,
+    pub point:  path::PathBuf,
+}
+
+/// Reason the device can't be overwritten right now.
+pub enum Busy {
+    Mounted {
+        dev:   path::PathBuf,
+        point: path::PathBuf,
+    },
+    Swap {
+        dev: path::PathBuf,
+    },
+    Held {
+        dev:    path::PathBuf,
+        holder: String,
+    },
+}
+
+impl Busy {
+    /// Mounts and swap can be released by us, stacked LVM, RAID or crypt devices are left to
+    /// the user.
+    pub fn releasable(&self) -> bool { !matches!(self, Busy::Held { .. }) }
+}
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Busy::Mounted { dev, point } => write!(f, "{dev:?} is mounted at {point:?}"),
+            Busy::Swap { dev } => write!(f, "{dev:?} is used as swap"),
+            Busy::Held { dev, holder } => write!(f, "{dev:?} is held by {holder}"),
+        }
+    }
+}
+
+/// Decodes `\040`-style escapes used by the kernel for blanks in paths.
+fn unescape(field: &str) -> path::PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut n = 0;
+    while n < bytes.len() {
+        let code = bytes
+            .get(n + 1..n + 4)
+            .filter(|_| bytes[n] == b'\\')
+            .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 8).ok());
+        match code {
+            Some(code) => {
+                out.push(code);
+                n += 4;
+            },
+            None => {
+                out.push(bytes[n]);
+                n += 1;
+            },
+        }
+    }
+    OsStr::from_bytes(&out).into()
+}
+
+/// Resolves the mount source to the device node.
+fn source_node(source: &path::Path) -> Option<path::PathBuf> {
+    // Sources like `tmpfs` are no paths, don't resolve them against the working directory
+    source
+        .is_absolute()
+        .then(|| fs::canonicalize(source).ok())
+        .flatten()
+}
+
+fn same_node(source: &path::Path, dev: &path::Path) -> bool {
+    source_node(source).is_some_and(|source| source == dev)
+}
+
+pub fn mounts() -> Result<Vec<Mount>> {
+    let data = fs::read_to_string("/proc/self/mountinfo").context("failed to read mount table")?;
+    Ok(data
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            let separator = fields.iter().position(|&field| field == "-")?;
+            Some(Mount {
+                number: fields.get(2)?.to_string(),
+                source: unescape(fields.get(separator + 2)?),
+                point:  unescape(fields.get(4)?),
+            })
+        })
+        .collect())
+}
+
+/// Active swap devices and files.
+pub fn swaps() -> Result<Vec<path::PathBuf>> {
+    let data = fs::read_to_string("/proc/swaps").context("failed to read swap table")?;
+    Ok(data
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .map(unescape)
+        .collect())
+}
+
+/// Name of the holder, with the mapping name of device mapper targets.
+fn holder_name(holder: &OsStr) -> String {
+    let holder = holder.to_string_lossy();
+    match fs::read_to_string(path::Path::new("/sys/block").join(&*holder).join("dm/name")) {
+        Ok(name) => format!("{holder} ({name})", name = name.trim()),
+        Err(_) => holder.to_string(),
+    }
+}
+
+fn nodes(device: &Device) -> Result<Vec<Node>> {
+    let name = device
+        .dev
+        .file_name()
+        .ok_or_else(|| eyre!("malformed device path"))?;
+    let sys = path::Path::new("/sys/block").join(name);
+
+    let mut nodes = Vec::new();
+    let mut add = |name: &OsStr, sys: path::PathBuf| -> Result<()> {
+        let number = fs::read_to_string(sys.join("dev"))
+            .with_context(|| format!("failed to read device number of {name:?}"))?;
+        nodes.push(Node {
+            dev: path::Path::new("/dev").join(name),
+            sys,
+            number: number.trim().to_string(),
+        });
+        Ok(())
+    };
+
+    add(name, sys.clone())?;
+    for entry in fs::read_dir(&sys).context("failed to list partitions")? {
+        let entry = entry?;
+        if entry.path().join("partition").exists() {
+            add(&entry.file_name(), entry.path())?;
+        }
+    }
+    Ok(nodes)
+}
+
+/// Lists everything using the device or its partitions.
+pub fn find(device: &Device) -> Result<Vec<Busy>> {
+    let nodes = nodes(device)?;
+    let mounts = mounts()?;
+    let swaps = swaps()?;
+    let mut busy = Vec::new();
+
+    for node in &nodes {
+        for mount in &mounts {
+            if mount.number == node.number || same_node(&mount.source, &node.dev) {
+                busy.push(Busy::Mounted {
+                    dev:   node.dev.clone(),
+                    point: mount.point.clone(),
+                });
+            }
+        }
+        if swaps.iter().any(|swap| same_node(swap, &node.dev)) {
+            busy.push(Busy::Swap {
+                dev: node.dev.clone(),
+            });
+        }
+        // Partitions have their holders listed too, only missing when there's nothing there
+        if let Ok(holders) = fs::read_dir(node.sys.join("holders")) {
+            for holder in holders {
+                busy.push(Busy::Held {
+                    dev:    node.dev.clone(),
+                    holder: holder_name(&holder?.file_name()),
+                });
+            }
+        }
+    }
+
+    Ok(busy)
+}
+
+fn path_cstr(path: &path::Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).with_context(|| format!("invalid path {path:?}"))
+}
+
+/// Unmounts and disables swap found by [`find`], nested mount points first.
+pub fn release(busy: &[Busy]) -> Result<()> {
+    let mut points: Vec<_> = busy
+        .iter()
+        .filter_map(|busy| match busy {
+            Busy::Mounted { point, .. } => Some(point),
+            _ => None,
+        })
+        .collect();
+    points.sort_by_key(|point| std::cmp::Reverse(point.components().count()));
+
+    for point in points {
+        let c_point = path_cstr(point)?;
+        if unsafe { libc::umount2(c_point.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to unmount {point:?}"));
+        }
+        info!("Unmounted {point:?}");
+    }
+
+    for busy in busy {
+        if let Busy::Swap { dev } = busy {
+            let c_dev = path_cstr(dev)?;
+            if unsafe { libc::swapoff(c_dev.as_ptr()) } != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("failed to disable swap on {dev:?}"));
+            }
+            info!("Disabled swap on {dev:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Collects whole disks under the block device, following partitions to their disk and
+/// device mapper or RAID devices to their members.
+fn disks_of(sys: &path::Path, disks: &mut Vec<OsString>) {
+    let Ok(sys) = fs::canonicalize(sys) else {
+        return;
+    };
+    if sys.join("partition").exists() {
+        if let Some(disk) = sys.parent() {
+            disks_of(disk, disks);
+        }
+        return;
+    }
+
+    let slaves: Vec<_> = fs::read_dir(sys.join("slaves"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .collect();
+    if slaves.is_empty() {
+        disks.extend(sys.file_name().map(OsStr::to_os_string));
+    }
+    for slave in slaves {
+        disks_of(&slave.path(), disks);
+    }
+}
+
+/// Names (like `sda` or `nvme0n1`) of the disks backing the root, boot and home filesystems
+/// and active swap.
+pub fn system_disks() -> Result<Vec<OsString>> {
+    let mut disks = Vec::new();
+
+    for mount in mounts()? {
+        if !SYSTEM_MOUNTS
+            .iter()
+            .any(|&point| mount.point == path::Path::new(point))
+        {
+            continue;
+        }
+        // Btrfs and alike report an anonymous number, the source names the device then
+        let by_number = path::Path::new("/sys/dev/block").join(&mount.number);
+        if by_number.exists() {
+            disks_of(&by_number, &mut disks);
+        } else if let Some(name) = source_node(&mount.source)
+            .as_deref()
+            .and_then(path::Path::file_name)
+        {
+            disks_of(&path::Path::new("/sys/class/block").join(name), &mut disks);
+        } else {
+            debug!(
+                "No block device behind {point:?} ({source:?})",
+                point = mount.point,
+                source = mount.source
+            );
+        }
+    }
+
+    for swap in swaps()? {
+        let Ok(meta) = fs::metadata(&swap) else {
+            continue;
+        };
+        // Swap file lives on the filesystem's device, partition is the device itself
+        let dev = if meta.is_file() {
+            meta.dev()
+        } else {
+            meta.rdev()
+        };
+        let number = format!("{}:{}", libc::major(dev), libc::minor(dev));
+        disks_of(&path::Path::new("/sys/dev/block").join(number), &mut disks);
+    }
+
+    disks.sort();
+    disks.dedup();
+    debug!("System disks: {disks:?}");
+    Ok(disks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_blanks() {
+        assert_eq!(
+            unescape(r"/media/USB\040stick\011x"),
+            path::Path::new("/media/USB stick\tx")
+        );
+        assert_eq!(unescape("/dev/sdb1"), path::Path::new("/dev/sdb1"));
+    }
+
+    #[test]
+    fn keeps_truncated_escapes() {
+        assert_eq!(unescape(r"/mnt/a\04"), path::Path::new(r"/mnt/a\04"));
+        assert_eq!(unescape(r"/mnt/a\"), path::Path::new(r"/mnt/a\"));
+        assert_eq!(unescape(r"/mnt/a\9xy"), path::Path::new(r"/mnt/a\9xy"));
+    }
+}
@@ -0,0 +1,317 @@
+use crate::tools::{be_u32, be_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs, io,
+    io::{Read, Seek, SeekFrom},
+    path,
+};
+
+const MAGIC: &[u8; 4] = b"QFI\xfb";
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_DATA_FILE: u64 = 1 << 2;
+const INCOMPAT_COMPRESSION: u64 = 1 << 3;
+const INCOMPAT_EXTL2: u64 = 1 << 4;
+const INCOMPAT_KNOWN: u64 =
+    INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_DATA_FILE | INCOMPAT_COMPRESSION | INCOMPAT_EXTL2;
+
+const CRYPT_NONE: u32 = 0;
+
+const COMPRESSION_ZLIB: u8 = 0;
+const COMPRESSION_ZSTD: u8 = 1;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_COMPRESSED: u64 = 1 << 62;
+const L2_ZERO: u64 = 1;
+
+/// Guest-visible contents of a QCOW2 image, presented as a raw stream.
+pub struct Qcow2Reader {
+    file:         fs::File,
+    file_size:    u64,
+    cluster_bits: u32,
+    compression:  u8,
+    size:         u64,
+    l1:           Vec<u64>,
+    l2:           Vec<u64>,
+    l2_index:     Option<usize>,
+    cluster:      Vec<u8>,
+    cluster_idx:  Option<u64>,
+    pos:          u64,
+}
+
+impl Qcow2Reader {
+    pub fn open(path: &path::Path) -> Result<Self> {
+        let mut file = fs::File::open(path).context("failed to open file")?;
+        let file_size = file.metadata().context("failed to stat file")?.len();
+
+        let mut header = [0u8; 112];
+        let got = file.read(&mut header).context("failed to read header")?;
+        if got < 72 || !header.starts_with(MAGIC) {
+            return Err(eyre!("Not a QCOW2 image"));
+        }
+
+        let version = be_u32(&header, 4);
+        if !(2..=3).contains(&version) {
+            return Err(eyre!("Unsupported QCOW2 version {version}"));
+        }
+
+        if be_u64(&header, 8) != 0 {
+            return Err(eyre!(
+                "QCOW2 images with a backing file are not supported, flatten it first"
+            ));
+        }
+
+        let cluster_bits = be_u32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(eyre!("Invalid QCOW2 cluster size 2^{cluster_bits}"));
+        }
+
+        let size = be_u64(&header, 24);
+
+        if be_u32(&header, 32) != CRYPT_NONE {
+            return Err(eyre!("Encrypted QCOW2 images are not supported"));
+        }
+
+        let l1_size = be_u32(&header, 36) as usize;
+        let l1_offset = be_u64(&header, 40);
+
+        let mut compression = COMPRESSION_ZLIB;
+        if version >= 3 {
+            let incompatible = be_u64(&header, 72);
+            if incompatible & !INCOMPAT_KNOWN != 0 {
+                return Err(eyre!("Unknown QCOW2 features {incompatible:#x}"));
+            }
+            if incompatible & INCOMPAT_CORRUPT != 0 {
+                return Err(eyre!("QCOW2 image is marked as corrupt"));
+            }
+            if incompatible & INCOMPAT_DATA_FILE != 0 {
+                return Err(eyre!(
+                    "QCOW2 images with an external data file are not supported"
+                ));
+            }
+            if incompatible & INCOMPAT_EXTL2 != 0 {
+                return Err(eyre!(
+                    "QCOW2 images with extended L2 entries are not supported"
+                ));
+            }
+            if incompatible & INCOMPAT_DIRTY != 0 {
+                warn!("QCOW2 image was not closed cleanly, refcounts may be stale");
+            }
+
+            let refcount_order = be_u32(&header, 96);
+            if refcount_order > 6 {
+                return Err(eyre!("Invalid QCOW2 refcount order {refcount_order}"));
+            }
+
+            let header_length = be_u32(&header, 100);
+            if incompatible & INCOMPAT_COMPRESSION != 0 && header_length > 104 {
+                compression = header[104];
+            }
+            if compression != COMPRESSION_ZLIB && compression != COMPRESSION_ZSTD {
+                return Err(eyre!("Unknown QCOW2 compression type {compression}"));
+            }
+        }
+
+        let refcount_offset = be_u64(&header, 48);
+        let refcount_clusters = be_u32(&header, 56) as u64;
+        let refcount_end = refcount_offset.checked_add(refcount_clusters << cluster_bits);
+        if refcount_end.is_none_or(|end| end > file_size) {
+            return Err(eyre!("QCOW2 refcount table lies outside of the file"));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_entries = cluster_size / 8;
+        if (l1_size as u64) < size.div_ceil(cluster_size * l2_entries) {
+            return Err(eyre!("QCOW2 L1 table too small for virtual size"));
+        }
+
+        let l1_end = l1_offset.checked_add(l1_size as u64 * 8);
+        if l1_end.is_none_or(|end| end > file_size) {
+            return Err(eyre!("QCOW2 L1 table lies outside of the file"));
+        }
+
+        let mut l1_raw = vec![0u8; l1_size * 8];
+        file.seek(SeekFrom::Start(l1_offset))
+            .context("failed to seek to L1 table")?;
+        file.read_exact(&mut l1_raw)
+            .context("failed to read L1 table")?;
+        let l1 = l1_raw
+            .chunks_exact(8)
+            .map(|entry| be_u64(entry, 0) & L1_OFFSET_MASK)
+            .collect();
+
+        debug!(
+            "QCOW2 v{version}, {size} bytes virtual, {cluster_size} bytes clusters, {l1_size} L1 \
+             entries"
+        );
+
+        Ok(Self {
+            file,
+            file_size,
+            cluster_bits,
+            compression,
+            size,
+            l1,
+            l2: Vec::new(),
+            l2_index: None,
+            cluster: vec![0u8; cluster_size as usize],
+            cluster_idx: None,
+            pos: 0,
+        })
+    }
+
+    fn load_l2(&mut self, index: usize) -> io::Result<()> {
+        if self.l2_index == Some(index) {
+            return Ok(());
+        }
+
+        let offset = self.l1[index];
+        let entries = 1usize << (self.cluster_bits - 3);
+        self.l2.clear();
+        if offset == 0 {
+            self.l2.resize(entries, 0);
+        } else {
+            let mut raw = vec![0u8; entries * 8];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut raw)?;
+            self.l2
+                .extend(raw.chunks_exact(8).map(|entry| be_u64(entry, 0)));
+        }
+        self.l2_index = Some(index);
+        Ok(())
+    }
+
+    fn load_cluster(&mut self, index: u64) -> io::Result<()> {
+        if self.cluster_idx == Some(index) {
+            return Ok(());
+        }
+
+        let l2_bits = self.cluster_bits - 3;
+        self.load_l2((index >> l2_bits) as usize)?;
+        let entry = self.l2[(index & ((1 << l2_bits) - 1)) as usize];
+
+        if entry & L2_COMPRESSED != 0 {
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & ((1 << 62) - 1)) >> offset_bits) + 1;
+            if offset >= self.file_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed cluster lies outside of the file",
+                ));
+            }
+            let len = (sectors * 512 - (offset & 511)).min(self.file_size - offset);
+
+            let mut compressed = vec![0u8; len as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut compressed)?;
+
+            match self.compression {
+                COMPRESSION_ZSTD => zstd::stream::read::Decoder::new(&compressed[..])?
+                    .read_exact(&mut self.cluster)?,
+                _ => flate2::read::DeflateDecoder::new(&compressed[..])
+                    .read_exact(&mut self.cluster)?,
+            }
+        } else {
+            let offset = entry & L2_OFFSET_MASK;
+            if offset == 0 || entry & L2_ZERO != 0 {
+                self.cluster.fill(0);
+            } else {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut self.cluster)?;
+            }
+        }
+
+        self.cluster_idx = Some(index);
+        Ok(())
+    }
+}
+
+impl Read for Qcow2Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.load_cluster(self.pos >> self.cluster_bits)?;
+
+        let in_cluster = (self.pos & ((1 << self.cluster_bits) - 1)) as usize;
+        let len = buf
+            .len()
+            .min(self.cluster.len() - in_cluster)
+            .min((self.size - self.pos) as usize);
+        buf[..len].copy_from_slice(&self.cluster[in_cluster..in_cluster + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    /// QCOW2 v3 image of 512 bytes clusters, with the first of two clusters allocated.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 2048];
+        image[..4].copy_from_slice(MAGIC);
+        image[4..8].copy_from_slice(&3u32.to_be_bytes());
+        image[20..24].copy_from_slice(&9u32.to_be_bytes());
+        image[24..32].copy_from_slice(&1024u64.to_be_bytes());
+        image[36..40].copy_from_slice(&1u32.to_be_bytes());
+        image[40..48].copy_from_slice(&512u64.to_be_bytes());
+        image[96..100].copy_from_slice(&4u32.to_be_bytes());
+        image[100..104].copy_from_slice(&104u32.to_be_bytes());
+        image[512..520].copy_from_slice(&(1024u64 | 1 << 63).to_be_bytes());
+        image[1024..1032].copy_from_slice(&(1536u64 | 1 << 63).to_be_bytes());
+        image[1536..].fill(0x5A);
+        image
+    }
+
+    fn open(data: &[u8]) -> Result<Qcow2Reader> { Qcow2Reader::open(temp_file(data).path()) }
+
+    #[test]
+    fn reads_guest_contents() {
+        let mut data = Vec::new();
+        open(&image()).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 1024);
+        assert!(data[..512].iter().all(|&b| b == 0x5A));
+        assert!(data[512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_image() {
+        let image = image();
+        assert!(open(&image[..64]).is_err());
+        assert!(open(&image[..516]).is_err());
+    }
+
+    #[test]
+    fn rejects_tables_outside_of_file() {
+        let mut bad = image();
+        bad[40..48].copy_from_slice(&(1u64 << 60).to_be_bytes());
+        assert!(open(&bad).is_err());
+
+        let mut bad = image();
+        bad[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(open(&bad).is_err());
+
+        let mut bad = image();
+        bad[48..56].copy_from_slice(&u64::MAX.to_be_bytes());
+        bad[56..60].copy_from_slice(&1u32.to_be_bytes());
+        assert!(open(&bad).is_err());
+    }
+
+    #[test]
+    fn rejects_compressed_cluster_past_end() {
+        let mut bad = image();
+        bad[1024..1032].copy_from_slice(&(1u64 << 62 | 4096).to_be_bytes());
+        let mut data = Vec::new();
+        let err = open(&bad).unwrap().read_to_end(&mut data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,29 +1,246 @@
-use crate::reader;
+#![allow(clippy::upper_case_acronyms)]
+
+#[cfg(feature = "parallel")]
+use crate::seekable;
+use crate::{bmap, cli, error::Error, qcow2, reader, simg, tar, vhd, vhdx, vmdk, zip};
 use color_eyre::eyre::{Context, Result, eyre};
 use dialoguer::{Select, theme::ColorfulTheme};
-use log::warn;
+use log::{debug, info, warn};
 use sha2::Digest;
 use std::{
-    fs, io,
-    io::{BufReader, Read},
+    fmt, fs, io,
+    io::{BufReader, Read, Seek, SeekFrom},
     path,
 };
 
+const SNIFF_SIZE: usize = 512;
+
+/// Extensions of uncompressed disk images, also used to spot them inside archives.
+const RAW_EXTS: [&str; 7] = ["ISO", "FS", "IMG", "IMA", "DD", "BIN", "RAW"];
+
 pub fn by_ext(ext: &str) -> Result<Box<dyn Decompressor>> {
     match ext {
-        "ISO" | "FS" | "IMG" | "IMA" | "DD" | "BIN" | "RAW" => Ok(reader::Direct::init()),
+        ext if RAW_EXTS.contains(&ext) => Ok(reader::Direct::init()),
         "BZ2" | "BZIP2" => Ok(reader::BZ2::init()),
         "GZ" | "GZIP" => Ok(reader::GZIP::init()),
         "XZ" | "LZMA" | "PIXZ" => Ok(reader::XZ::init()),
         "ZST" | "ZSTD" => Ok(reader::ZSTD::init()),
         "LZ4" => Ok(reader::LZ4::init()),
+        "QCOW2" | "QCOW" => Ok(reader::QCOW2::init()),
+        "VMDK" => Ok(reader::VMDK::init()),
+        "VHD" | "VPC" => Ok(reader::VHD::init()),
+        "VHDX" => Ok(reader::VHDX::init()),
+        "SIMG" => Ok(reader::SIMG::init()),
+        "ZIP" => Ok(reader::ZIP::init()),
+        "TAR" => Ok(reader::TAR::wrap(reader::Direct::init())),
+        "TGZ" => by_ext("TAR.GZ"),
+        "TBZ" | "TBZ2" => by_ext("TAR.BZ2"),
+        "TXZ" => by_ext("TAR.XZ"),
+        "TZST" => by_ext("TAR.ZST"),
+        ext if ext.starts_with("TAR.") => by_ext(&ext[4..]).map(reader::TAR::wrap),
         _ => Err(eyre!("unrecognized compression {ext}")),
     }
 }
 
+/// Advances `reader` by `len` bytes, discarding the data.
+pub fn skip(reader: &mut dyn Read, len: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(len as u64), &mut io::sink())?;
+    if skipped != len as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "image ended prematurely",
+        ));
+    }
+    Ok(())
+}
+
+/// Image data read in order, able to pass over holes of the block map.
+pub trait Stream: Read {
+    /// Advances by `len` bytes, discarding the data.
+    fn skip(&mut self, len: usize) -> io::Result<()>;
+
+    /// Whether `skip` gets anywhere without reading the data through.
+    fn can_seek(&self) -> bool { false }
+}
+
+/// Stream of a format that has to be decoded to get anywhere, so holes are read through.
+struct Discard(Box<dyn Read>);
+
+impl Read for Discard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+}
+
+impl Stream for Discard {
+    fn skip(&mut self, len: usize) -> io::Result<()> { skip(&mut self.0, len) }
+}
+
+impl Stream for BufReader<fs::File> {
+    fn skip(&mut self, len: usize) -> io::Result<()> { self.seek_relative(len as i64) }
+
+    fn can_seek(&self) -> bool { true }
+}
+
+fn is_raw_image(name: &str) -> bool {
+    path::Path::new(name)
+        .extension()
+        .is_some_and(|ext| RAW_EXTS.contains(&ext.to_string_lossy().to_ascii_uppercase().as_str()))
+}
+
+/// Picks the disk image among archive members: the one given by `--member`, or asking when there
+/// are several candidates. Members with a raw image extension are preferred over the rest.
+fn pick_member<T: fmt::Display>(mut members: Vec<T>, name: impl Fn(&T) -> &str) -> Result<T> {
+    let args = cli::args();
+    if let Some(wanted) = &args.member {
+        return members
+            .into_iter()
+            .find(|member| name(member).eq(wanted))
+            .ok_or_else(|| eyre!("Archive has no member {wanted:?}"));
+    }
+
+    if members.iter().any(|member| is_raw_image(name(member))) {
+        members.retain(|member| is_raw_image(name(member)));
+    }
+
+    match members.len() {
+        0 => Err(eyre!("Archive contains no files")),
+        1 => Ok(members.remove(0)),
+        count if args.non_interactive => {
+            Err(eyre!("{count} images in archive, pick one with --member"))
+        },
+        _ => {
+            info!("Multiple images in archive");
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .default(0)
+                .with_prompt("Select image to write [q to abort]:")
+                .items(&members)
+                .interact_opt()?;
+            match selection {
+                Some(selection) => Ok(members.remove(selection)),
+                None => Err(Error::Aborted.into()),
+            }
+        },
+    }
+}
+
+/// Upper-cased extension of the file, keeping `tar` of double extensions like `.tar.xz`.
+fn extension(path: &path::Path) -> Option<String> {
+    let ext = path.extension()?.to_string_lossy().to_ascii_uppercase();
+    let inner = path::Path::new(path.file_stem()?)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_uppercase());
+    match inner.as_deref() {
+        Some("TAR") => Some(format!("TAR.{ext}")),
+        _ => Some(ext),
+    }
+}
+
+fn is_tar(head: &[u8]) -> bool { head.get(257..262) == Some(b"ustar") }
+
+/// Recognizes stream compressors by their signature.
+fn compression_by_magic(head: &[u8]) -> Option<Box<dyn Decompressor>> {
+    let reader = match head {
+        [0x1F, 0x8B, ..] => reader::GZIP::init(),
+        [b'B', b'Z', b'h', ..] => reader::BZ2::init(),
+        [0xFD, b'7', b'z', b'X', b'Z', 0x00, ..] => reader::XZ::init(),
+        [0x28, 0xB5, 0x2F, 0xFD, ..] => reader::ZSTD::init(),
+        [0x04, 0x22, 0x4D, 0x18, ..] => reader::LZ4::init(),
+        _ => return None,
+    };
+    Some(reader)
+}
+
+/// Looks into the decompressed stream for a tar header.
+fn sniff_tar(
+    compression: Box<dyn Decompressor>,
+    path: &path::Path,
+) -> Result<Box<dyn Decompressor>> {
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    compression
+        .open_reader(path)?
+        .take(SNIFF_SIZE as u64)
+        .read_to_end(&mut head)
+        .context("failed to decompress header")?;
+    if is_tar(&head) {
+        Ok(reader::TAR::wrap(compression))
+    } else {
+        Ok(compression)
+    }
+}
+
+/// Recognizes other formats by their signature in the first (or, for VHD, last) sector of the
+/// file.
+fn by_magic(head: &[u8], tail: &[u8]) -> Option<Box<dyn Decompressor>> {
+    let reader = match head {
+        [b'Q', b'F', b'I', 0xFB, ..] => reader::QCOW2::init(),
+        [b'K', b'D', b'M', b'V', ..] => reader::VMDK::init(),
+        [0x3A, 0xFF, 0x26, 0xED, ..] => reader::SIMG::init(),
+        [b'P', b'K', 0x03, 0x04, ..] | [b'P', b'K', 0x05, 0x06, ..] => reader::ZIP::init(),
+        _ if head.starts_with(b"# Disk DescriptorFile") => reader::VMDK::init(),
+        _ if head.starts_with(b"vhdxfile") => reader::VHDX::init(),
+        _ if head.starts_with(b"conectix") => reader::VHD::init(),
+        _ if is_tar(head) => reader::TAR::wrap(reader::Direct::init()),
+        // Fixed VHD has only a footer, 511 bytes long in some old images
+        _ if tail.windows(8).take(2).any(|sig| sig == b"conectix") => reader::VHD::init(),
+        _ => return None,
+    };
+    Some(reader)
+}
+
+/// Picks the reader by file contents, falling back to extension for formats lacking a signature.
+pub fn detect(path: &path::Path) -> Result<Box<dyn Decompressor>> {
+    let mut file = fs::File::open(path).context("failed to open file")?;
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    file.by_ref()
+        .take(SNIFF_SIZE as u64)
+        .read_to_end(&mut head)
+        .context("failed to read header")?;
+
+    let mut tail = Vec::with_capacity(SNIFF_SIZE);
+    let len = file.metadata().context("failed to stat file")?.len();
+    if len >= SNIFF_SIZE as u64 {
+        file.seek(SeekFrom::End(-(SNIFF_SIZE as i64)))
+            .context("failed to seek to trailer")?;
+        file.read_to_end(&mut tail)
+            .context("failed to read trailer")?;
+    }
+
+    let by_name = extension(path).and_then(|ext| by_ext(&ext).ok());
+    let by_content = match compression_by_magic(&head) {
+        Some(compression) => Some(sniff_tar(compression, path)?),
+        None => by_magic(&head, &tail),
+    };
+
+    match (by_content, by_name) {
+        (Some(by_content), Some(by_name)) => {
+            if by_content.get_name() != by_name.get_name() {
+                warn!(
+                    "File extension suggests {ext}, but contents look {content}; trusting contents",
+                    ext = by_name.get_name(),
+                    content = by_content.get_name()
+                );
+            }
+            Ok(by_content)
+        },
+        (Some(by_content), None) => {
+            debug!("Detected {} by contents", by_content.get_name());
+            Ok(by_content)
+        },
+        (None, Some(by_name)) => Ok(by_name),
+        (None, None) => {
+            let has_mbr = head.len() == SNIFF_SIZE && head[510] == 0x55 && head[511] == 0xAA;
+            if has_mbr {
+                debug!("No known signature, but MBR present; assuming raw image");
+                Ok(reader::Direct::init())
+            } else {
+                Err(eyre!("unrecognized image format"))
+            }
+        },
+    }
+}
+
 pub trait Decompressor
 where
-    Self: 'static + Send,
+    Self: 'static + Send + Sync,
 {
     fn init() -> Box<dyn Decompressor>
     where
@@ -31,36 +248,28 @@ where
     {
         Box::new(Self::default())
     }
+    /// Chooses what to read out of archives holding several files, called before `open_reader`.
+    fn prepare(&mut self, _path: &path::Path) -> Result<()> { Ok(()) }
+    /// Name of the archive member being read, if any.
+    fn get_member(&self) -> Option<&str> { None }
+    /// Length of the image when known without reading it all, like the size of archive member.
+    fn get_size(&self) -> Option<usize> { None }
     fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>>;
+    /// Like `open_reader`, but seeking over holes where the format allows it.
+    fn open_stream(&self, path: &path::Path) -> Result<Box<dyn Stream>> {
+        Ok(Box::new(Discard(self.open_reader(path)?)))
+    }
     fn get_size_sum(&self, path: &path::Path) -> Result<([u8; 32], usize)> {
         let mut reader = self.open_reader(path)?;
-
-        let mut boot = [0u8; 512];
-        reader
-            .read_exact(&mut boot)
-            .context("failed to read boot")?;
-
-        if boot[510] != 0x55 || boot[511] != 0xAA {
-            warn!("This doesn't look like a hybrid image (lacks MBR signature)");
-            let selection = Select::with_theme(&ColorfulTheme::default())
-                .default(0)
-                .with_prompt("What to do:")
-                .items(&["Abort", "Continue"])
-                .interact()?;
-            if selection == 0 {
-                return Err(eyre!("Bad image format"));
-            }
-        }
-
         let mut file_sum = sha2::Sha256::new();
-        file_sum.update(&boot);
-
         let size = io::copy(&mut reader, &mut file_sum).context("failed to measure output")?;
         let checksum = file_sum.finalize();
         let mut bin_checksum = [0u8; 32];
         bin_checksum.copy_from_slice(&checksum);
-        Ok((bin_checksum, 512 + size as usize))
+        Ok((bin_checksum, size as usize))
     }
+    /// Ranges of the image holding data, for formats that know which parts are holes.
+    fn block_map(&self, _path: &path::Path) -> Result<Option<bmap::BlockMap>> { Ok(None) }
     fn get_name(&self) -> &str;
 }
 
@@ -74,6 +283,11 @@ impl Decompressor for Direct {
         Ok(Box::new(raw_reader))
     }
 
+    fn open_stream(&self, path: &path::Path) -> Result<Box<dyn Stream>> {
+        let f = fs::File::open(path).context("failed to open file")?;
+        Ok(Box::new(BufReader::new(f)))
+    }
+
     fn get_name(&self) -> &str { "uncompressed" }
 }
 
@@ -93,11 +307,35 @@ impl Decompressor for BZ2 {
 #[derive(Debug, Clone, Default)]
 pub struct XZ {}
 
+/// Threads to decompress on, one per core.
+#[cfg(feature = "parallel")]
+fn threads() -> usize { std::thread::available_parallelism().map_or(1, std::num::NonZero::get) }
+
+/// Multi-threaded decoder. Blocks of streams made by `xz -T` or `pixz` are decoded in
+/// parallel, single-block streams fall back to a single thread.
+#[cfg(feature = "parallel")]
+fn xz_stream() -> Result<liblzma::stream::Stream> {
+    // Like xz does, let the threads use up to a quarter of the memory
+    let memory = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) * libc::sysconf(libc::_SC_PAGESIZE) };
+    liblzma::stream::MtStreamBuilder::new()
+        .threads(threads() as u32)
+        .memlimit_threading((memory / 4).max(1) as u64)
+        .memlimit_stop(u64::MAX)
+        .decoder()
+        .context("failed to set up decompression")
+}
+
+#[cfg(not(feature = "parallel"))]
+fn xz_stream() -> Result<liblzma::stream::Stream> {
+    liblzma::stream::Stream::new_stream_decoder(u64::MAX, 0)
+        .context("failed to set up decompression")
+}
+
 impl Decompressor for XZ {
     fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
         let file = fs::File::open(path).context("failed to open file")?;
         let reader = BufReader::new(file);
-        let decompress_reader = liblzma::bufread::XzDecoder::new(reader);
+        let decompress_reader = liblzma::bufread::XzDecoder::new_stream(reader, xz_stream()?);
         Ok(Box::new(decompress_reader))
     }
 
@@ -124,10 +362,37 @@ pub struct ZSTD {}
 impl Decompressor for ZSTD {
     fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
         let file = fs::File::open(path).context("failed to open file")?;
+        // Frames of the seekable format can be decompressed independently
+        #[cfg(feature = "parallel")]
+        if let Some(frames) = seekable::frames(&file)?.filter(|frames| frames.len() > 1)
+            && threads() > 1
+        {
+            return Ok(Box::new(seekable::FrameReader::new(
+                path,
+                frames,
+                threads(),
+            )?));
+        }
         let decompress_reader = zstd::Decoder::new(file).context("failed to decompress")?;
         Ok(Box::new(decompress_reader))
     }
 
+    fn open_stream(&self, path: &path::Path) -> Result<Box<dyn Stream>> {
+        // The seek table lets frames before the wanted data go undecompressed
+        #[cfg(feature = "parallel")]
+        if let Some(frames) =
+            seekable::frames(&fs::File::open(path).context("failed to open file")?)?
+                .filter(|frames| frames.len() > 1)
+        {
+            return Ok(Box::new(seekable::FrameReader::new(
+                path,
+                frames,
+                threads(),
+            )?));
+        }
+        Ok(Box::new(Discard(self.open_reader(path)?)))
+    }
+
     fn get_name(&self) -> &str { "compressed with ZSTD" }
 }
 
@@ -144,3 +409,214 @@ impl Decompressor for LZ4 {
 
     fn get_name(&self) -> &str { "compressed with LZ4" }
 }
+
+#[derive(Debug, Clone, Default)]
+pub struct QCOW2 {}
+
+impl Decompressor for QCOW2 {
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let reader = qcow2::Qcow2Reader::open(path)?;
+        Ok(Box::new(reader))
+    }
+
+    fn get_name(&self) -> &str { "QCOW2 virtual disk" }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VMDK {}
+
+impl Decompressor for VMDK {
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let reader = vmdk::VmdkReader::open(path)?;
+        Ok(Box::new(reader))
+    }
+
+    fn get_name(&self) -> &str { "VMDK virtual disk" }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VHD {}
+
+impl Decompressor for VHD {
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let reader = vhd::VhdReader::open(path)?;
+        Ok(Box::new(reader))
+    }
+
+    fn get_name(&self) -> &str { "VHD virtual disk" }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VHDX {}
+
+impl Decompressor for VHDX {
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let reader = vhdx::VhdxReader::open(path)?;
+        Ok(Box::new(reader))
+    }
+
+    fn get_name(&self) -> &str { "VHDX virtual disk" }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SIMG {}
+
+impl Decompressor for SIMG {
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let reader = simg::SparseReader::open(path)?;
+        Ok(Box::new(reader))
+    }
+
+    fn block_map(&self, path: &path::Path) -> Result<Option<bmap::BlockMap>> {
+        simg::SparseReader::block_map(path).map(Some)
+    }
+
+    fn get_name(&self) -> &str { "Android sparse image" }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ZIP {
+    entry: Option<zip::Entry>,
+}
+
+impl Decompressor for ZIP {
+    fn prepare(&mut self, path: &path::Path) -> Result<()> {
+        let entries = zip::entries(path)?
+            .into_iter()
+            .filter(|entry| !entry.is_dir())
+            .collect();
+        let entry = pick_member(entries, |entry: &zip::Entry| &entry.name)?;
+        info!("Using {:?} from archive", entry.name);
+        self.entry = Some(entry);
+        Ok(())
+    }
+
+    fn get_member(&self) -> Option<&str> { self.entry.as_ref().map(|entry| entry.name.as_str()) }
+
+    fn get_size(&self) -> Option<usize> { self.entry.as_ref().map(|entry| entry.size) }
+
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let entry = self
+            .entry
+            .as_ref()
+            .ok_or_else(|| eyre!("No archive entry selected"))?;
+        let reader = zip::ZipReader::open(path, entry)?;
+        Ok(Box::new(reader))
+    }
+
+    fn get_name(&self) -> &str { "ZIP archive" }
+}
+
+pub struct TAR {
+    inner:  Box<dyn Decompressor>,
+    name:   String,
+    member: Option<tar::Member>,
+}
+
+impl TAR {
+    pub fn wrap(inner: Box<dyn Decompressor>) -> Box<dyn Decompressor> {
+        let name = match inner.get_name() {
+            "uncompressed" => "tar archive".to_string(),
+            compression => format!("tar archive {compression}"),
+        };
+        Box::new(Self {
+            inner,
+            name,
+            member: None,
+        })
+    }
+}
+
+impl Decompressor for TAR {
+    fn prepare(&mut self, path: &path::Path) -> Result<()> {
+        let mut reader = self.inner.open_stream(path)?;
+        // Reading through the whole archive for more images could take as long as writing
+        let members = match &cli::args().member {
+            Some(wanted) => tar::members(&mut *reader, |name| name == wanted)?,
+            None => tar::members(&mut *reader, is_raw_image)?,
+        };
+        let member = pick_member(members, |member: &tar::Member| &member.name)?;
+        info!("Using {:?} from archive", member.name);
+        self.member = Some(member);
+        Ok(())
+    }
+
+    fn get_member(&self) -> Option<&str> { self.member.as_ref().map(|member| member.name.as_str()) }
+
+    fn get_size(&self) -> Option<usize> { self.member.as_ref().map(|member| member.size) }
+
+    fn open_reader(&self, path: &path::Path) -> Result<Box<dyn Read>> {
+        let member = self
+            .member
+            .as_ref()
+            .ok_or_else(|| eyre!("No archive member selected"))?;
+        let mut reader = self.inner.open_stream(path)?;
+        reader
+            .skip(member.offset)
+            .context("failed to seek to archive member")?;
+        Ok(Box::new(reader.take(member.size as u64)))
+    }
+
+    fn get_name(&self) -> &str { &self.name }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Name of the reader picked for `data` stored in a file with given extension.
+    fn detect_named(ext: &str, data: &[u8]) -> Result<String> {
+        let mut file = tempfile::Builder::new().suffix(ext).tempfile().unwrap();
+        file.write_all(data).unwrap();
+        detect(file.path()).map(|reader| reader.get_name().to_string())
+    }
+
+    fn mbr() -> Vec<u8> {
+        let mut mbr = vec![0u8; 512];
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        mbr
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn detects_misnamed_image_by_contents() {
+        let packed = gzip(&mbr());
+        assert_eq!(detect_named("", &packed).unwrap(), "compressed with GZIP");
+        // Extension disagreeing with the contents only warns, contents win
+        assert_eq!(
+            detect_named(".xz", &packed).unwrap(),
+            "compressed with GZIP"
+        );
+        assert_eq!(
+            detect_named(".IMG", b"QFI\xfb").unwrap(),
+            "QCOW2 virtual disk"
+        );
+
+        let mut vhd = vec![0u8; 1024];
+        vhd[512..520].copy_from_slice(b"conectix");
+        assert_eq!(detect_named(".bin", &vhd).unwrap(), "VHD virtual disk");
+
+        let mut tar = mbr();
+        tar[257..263].copy_from_slice(b"ustar\0");
+        assert_eq!(
+            detect_named(".gz", &gzip(&tar)).unwrap(),
+            "tar archive compressed with GZIP"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_extension_without_signature() {
+        let data = [0u8; 1024];
+        assert_eq!(detect_named(".iso", &data).unwrap(), "uncompressed");
+        assert_eq!(detect_named(".Img", &data).unwrap(), "uncompressed");
+        assert_eq!(detect_named("", &mbr()).unwrap(), "uncompressed");
+        assert!(detect_named("", &data).is_err());
+        assert!(detect_named(".txt", &data).is_err());
+    }
+}
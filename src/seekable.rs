@@ -0,0 +1,329 @@
+use crate::{
+    reader::{self, Stream},
+    tools::le_u32,
+};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Read},
+    os::unix::fs::FileExt,
+    path,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+
+const SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+const SKIPPABLE_HEADER_SIZE: usize = 8;
+const FOOTER_SIZE: usize = 9;
+const CHECKSUM_FLAG: u8 = 0x80;
+const RESERVED_BITS: u8 = 0x7C;
+
+/// Frames decompressed ahead of the reader, per worker thread.
+const AHEAD: usize = 2;
+
+/// Independently compressed frame of a seekable zstd file.
+pub struct Frame {
+    offset: u64,
+    len:    usize,
+    size:   usize,
+}
+
+/// Reads the seek table ending a file in zstd seekable format. Returns `None` for plain zstd
+/// files. Frame checksums of the table are left out, zstd frames carry their own.
+pub fn frames(file: &fs::File) -> Result<Option<Vec<Frame>>> {
+    let file_len = file.metadata().context("failed to stat file")?.len() as usize;
+    if file_len < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut footer = [0u8; FOOTER_SIZE];
+    file.read_exact_at(&mut footer, (file_len - FOOTER_SIZE) as u64)
+        .context("failed to read seek table footer")?;
+    if le_u32(&footer, 5) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    let descriptor = footer[4];
+    if descriptor & RESERVED_BITS != 0 {
+        return Err(eyre!("Unsupported seek table descriptor {descriptor:#04x}"));
+    }
+
+    let entry_size = if descriptor & CHECKSUM_FLAG != 0 {
+        12
+    } else {
+        8
+    };
+    let table_len = (le_u32(&footer, 0) as usize)
+        .checked_mul(entry_size)
+        .filter(|&len| len + SKIPPABLE_HEADER_SIZE + FOOTER_SIZE <= file_len)
+        .ok_or_else(|| eyre!("Seek table larger than the file"))?;
+    let frame_len = SKIPPABLE_HEADER_SIZE + table_len + FOOTER_SIZE;
+
+    let mut table = vec![0u8; frame_len];
+    file.read_exact_at(&mut table, (file_len - frame_len) as u64)
+        .context("failed to read seek table")?;
+    if le_u32(&table, 0) != SKIPPABLE_MAGIC
+        || le_u32(&table, 4) as usize != frame_len - SKIPPABLE_HEADER_SIZE
+    {
+        return Err(eyre!("Invalid seek table header"));
+    }
+
+    let mut offset = 0;
+    let frames: Vec<_> = table[SKIPPABLE_HEADER_SIZE..SKIPPABLE_HEADER_SIZE + table_len]
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            let frame = Frame {
+                offset,
+                len: le_u32(entry, 0) as usize,
+                size: le_u32(entry, 4) as usize,
+            };
+            offset += frame.len as u64;
+            frame
+        })
+        .collect();
+    if offset as usize + frame_len != file_len {
+        return Err(eyre!("Seek table doesn't match the file"));
+    }
+
+    debug!("Seekable zstd, {count} frames", count = frames.len());
+    Ok(Some(frames))
+}
+
+fn decompress(file: &fs::File, frame: &Frame) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; frame.len];
+    file.read_exact_at(&mut data, frame.offset)?;
+    let data = zstd::bulk::decompress(&data, frame.size)?;
+    if data.len() != frame.size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame size doesn't match seek table",
+        ));
+    }
+    Ok(data)
+}
+
+/// Decompresses frames on a pool of threads, yielding the data in order.
+pub struct FrameReader {
+    frames:  Arc<Vec<Frame>>,
+    jobs:    Option<mpsc::Sender<usize>>,
+    results: mpsc::Receiver<(usize, io::Result<Vec<u8>>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    count:   usize,
+    ahead:   usize,
+    /// Frames handed to the workers so far.
+    queued:  usize,
+    /// Frames decompressed before their turn.
+    pending: BTreeMap<usize, Vec<u8>>,
+    current: Vec<u8>,
+    pos:     usize,
+    next:    usize,
+}
+
+impl FrameReader {
+    pub fn new(path: &path::Path, frames: Vec<Frame>, threads: usize) -> Result<Self> {
+        let count = frames.len();
+        let frames = Arc::new(frames);
+        let (jobs, job_rx) = mpsc::channel::<usize>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, results) = mpsc::channel();
+
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            // Each worker reads on its own, positioned reads don't share any state
+            let file = fs::File::open(path).context("failed to open file")?;
+            let frames = Arc::clone(&frames);
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            workers.push(thread::spawn(move || {
+                loop {
+                    // Sender is gone once the reader is dropped
+                    let Ok(index) = job_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let result = decompress(&file, &frames[index]);
+                    if result_tx.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        let mut reader = Self {
+            frames,
+            jobs: Some(jobs),
+            results,
+            workers,
+            count,
+            ahead: threads * AHEAD,
+            queued: 0,
+            pending: BTreeMap::new(),
+            current: Vec::new(),
+            pos: 0,
+            next: 0,
+        };
+        reader.queue();
+        Ok(reader)
+    }
+
+    /// Keeps the workers busy with the frames coming next.
+    fn queue(&mut self) {
+        let Some(jobs) = &self.jobs else {
+            return;
+        };
+        // Frames skipped over are never handed out
+        self.queued = self.queued.max(self.next);
+        while self.queued < self.count && self.queued < self.next + self.ahead {
+            if jobs.send(self.queued).is_err() {
+                return;
+            }
+            self.queued += 1;
+        }
+    }
+
+    fn take(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(data) = self.pending.remove(&index) {
+                return Ok(data);
+            }
+            let (done, result) = self
+                .results
+                .recv()
+                .map_err(|_| io::Error::other("decompression workers vanished"))?;
+            // Frames queued before a skip passed them by are dropped
+            if done >= index {
+                self.pending.insert(done, result?);
+            }
+        }
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            if self.next == self.count {
+                return Ok(0);
+            }
+            self.current = self.take(self.next)?;
+            self.pos = 0;
+            self.next += 1;
+            self.queue();
+        }
+
+        let len = buf.len().min(self.current.len() - self.pos);
+        buf[..len].copy_from_slice(&self.current[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+impl Stream for FrameReader {
+    fn skip(&mut self, mut len: usize) -> io::Result<()> {
+        let rest = len.min(self.current.len() - self.pos);
+        self.pos += rest;
+        len -= rest;
+
+        // Whole frames are passed over without decompressing them
+        while self.next < self.count && self.frames[self.next].size <= len {
+            len -= self.frames[self.next].size;
+            self.pending.remove(&self.next);
+            self.next += 1;
+        }
+        self.queue();
+        reader::skip(self, len)
+    }
+
+    fn can_seek(&self) -> bool { true }
+}
+
+impl Drop for FrameReader {
+    fn drop(&mut self) {
+        // Workers stop once there are no more jobs
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    const FRAME_SIZE: usize = 4096;
+
+    /// Three frames of distinct data, followed by their seek table.
+    fn seekable() -> (Vec<u8>, Vec<u8>) {
+        let data: Vec<u8> = (0..3 * FRAME_SIZE).map(|n| (n / 7) as u8).collect();
+        let mut file = Vec::new();
+        let mut table = Vec::new();
+        for frame in data.chunks(FRAME_SIZE) {
+            let compressed = zstd::bulk::compress(frame, 3).unwrap();
+            table.extend((compressed.len() as u32).to_le_bytes());
+            table.extend((frame.len() as u32).to_le_bytes());
+            file.extend(compressed);
+        }
+        file.extend(SKIPPABLE_MAGIC.to_le_bytes());
+        file.extend(((table.len() + FOOTER_SIZE) as u32).to_le_bytes());
+        file.extend(table);
+        file.extend(3u32.to_le_bytes());
+        file.push(0);
+        file.extend(SEEKABLE_MAGIC.to_le_bytes());
+        (file, data)
+    }
+
+    #[test]
+    fn reads_frames_in_order() {
+        let (image, data) = seekable();
+        let file = temp_file(&image);
+        let frames = frames(file.as_file()).unwrap().unwrap();
+        assert_eq!(frames.len(), 3);
+
+        let mut read = Vec::new();
+        FrameReader::new(file.path(), frames, 2)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn skips_whole_frames() {
+        let (image, data) = seekable();
+        let file = temp_file(&image);
+        let frames = frames(file.as_file()).unwrap().unwrap();
+        let mut reader = FrameReader::new(file.path(), frames, 2).unwrap();
+
+        let mut read = vec![0u8; 100];
+        reader.read_exact(&mut read).unwrap();
+        // Rest of the first frame, the second one and part of the third
+        reader.skip(2 * FRAME_SIZE).unwrap();
+        read.clear();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data[2 * FRAME_SIZE + 100..]);
+        assert!(reader.skip(1).is_err());
+    }
+
+    #[test]
+    fn ignores_plain_zstd() {
+        let plain = temp_file(&zstd::bulk::compress(&[0u8; FRAME_SIZE], 3).unwrap());
+        assert!(frames(plain.as_file()).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_table() {
+        let (image, _) = seekable();
+        // Frames missing their start
+        let truncated = temp_file(&image[10..]);
+        assert!(frames(truncated.as_file()).is_err());
+
+        // Entry count beyond the table
+        let mut oversized = image.clone();
+        let count = oversized.len() - FOOTER_SIZE;
+        oversized[count..count + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(frames(temp_file(&oversized).as_file()).is_err());
+    }
+}
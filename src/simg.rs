@@ -0,0 +1,343 @@
+use crate::{
+    bmap,
+    tools::{le_u16, le_u32},
+};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs, io,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path,
+};
+
+const MAGIC: u32 = 0xED26_FF3A;
+const MAJOR_VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+struct Header {
+    file_header:  usize,
+    chunk_header: usize,
+    block_size:   usize,
+    blocks:       usize,
+    chunks:       usize,
+}
+
+struct Chunk {
+    kind:      u16,
+    len:       usize,
+    data_size: usize,
+}
+
+impl Header {
+    fn read(file: &mut impl Read) -> Result<Self> {
+        let mut buf = [0u8; FILE_HEADER_SIZE];
+        file.read_exact(&mut buf)
+            .context("failed to read sparse header")?;
+        if le_u32(&buf, 0) != MAGIC {
+            return Err(eyre!("Not an Android sparse image"));
+        }
+        if le_u16(&buf, 4) != MAJOR_VERSION {
+            return Err(eyre!(
+                "Unsupported sparse image version {}",
+                le_u16(&buf, 4)
+            ));
+        }
+
+        let header = Self {
+            file_header:  le_u16(&buf, 8) as usize,
+            chunk_header: le_u16(&buf, 10) as usize,
+            block_size:   le_u32(&buf, 12) as usize,
+            blocks:       le_u32(&buf, 16) as usize,
+            chunks:       le_u32(&buf, 20) as usize,
+        };
+        if header.file_header < FILE_HEADER_SIZE || header.chunk_header < CHUNK_HEADER_SIZE {
+            return Err(eyre!("Invalid sparse image header sizes"));
+        }
+        if header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+            return Err(eyre!(
+                "Invalid sparse image block size {}",
+                header.block_size
+            ));
+        }
+
+        // Skip the rest of the header written by newer tools
+        io::copy(
+            &mut file.take((header.file_header - FILE_HEADER_SIZE) as u64),
+            &mut io::sink(),
+        )?;
+        Ok(header)
+    }
+
+    fn read_chunk(&self, file: &mut impl Read) -> Result<Chunk> {
+        let mut buf = [0u8; CHUNK_HEADER_SIZE];
+        file.read_exact(&mut buf)
+            .context("failed to read chunk header")?;
+        io::copy(
+            &mut file.take((self.chunk_header - CHUNK_HEADER_SIZE) as u64),
+            &mut io::sink(),
+        )?;
+
+        let kind = le_u16(&buf, 0);
+        let len = le_u32(&buf, 4) as usize * self.block_size;
+        let data_size = (le_u32(&buf, 8) as usize)
+            .checked_sub(self.chunk_header)
+            .ok_or_else(|| eyre!("Invalid chunk size"))?;
+
+        let expected = match kind {
+            CHUNK_RAW => len,
+            CHUNK_FILL | CHUNK_CRC32 => 4,
+            CHUNK_DONT_CARE => 0,
+            _ => return Err(eyre!("Unknown sparse chunk type {kind:#06x}")),
+        };
+        if data_size != expected {
+            return Err(eyre!("Sparse chunk {kind:#06x} has invalid size"));
+        }
+
+        Ok(Chunk {
+            kind,
+            len,
+            data_size,
+        })
+    }
+
+    fn size(&self) -> usize { self.blocks * self.block_size }
+}
+
+/// Expanded contents of an Android sparse image, presented as a raw stream.
+pub struct SparseReader {
+    file:        BufReader<fs::File>,
+    header:      Header,
+    chunks_left: usize,
+    kind:        u16,
+    left:        usize,
+    fill:        [u8; 4],
+    filled:      usize,
+    crc:         crc32fast::Hasher,
+}
+
+impl SparseReader {
+    pub fn open(path: &path::Path) -> Result<Self> {
+        let file = fs::File::open(path).context("failed to open file")?;
+        let mut file = BufReader::new(file);
+        let header = Header::read(&mut file)?;
+        debug!(
+            "Android sparse image, {blocks} blocks of {size} bytes in {chunks} chunks",
+            blocks = header.blocks,
+            size = header.block_size,
+            chunks = header.chunks
+        );
+
+        Ok(Self {
+            file,
+            chunks_left: header.chunks,
+            header,
+            kind: CHUNK_DONT_CARE,
+            left: 0,
+            fill: [0u8; 4],
+            filled: 0,
+            crc: crc32fast::Hasher::new(),
+        })
+    }
+
+    /// Lists the parts of the image holding data, `DONT_CARE` chunks being the holes.
+    pub fn block_map(path: &path::Path) -> Result<bmap::BlockMap> {
+        let mut file = BufReader::new(fs::File::open(path).context("failed to open file")?);
+        let header = Header::read(&mut file)?;
+
+        let mut ranges: Vec<bmap::Range> = Vec::new();
+        let mut offset = 0;
+        for _ in 0..header.chunks {
+            let chunk = header.read_chunk(&mut file)?;
+            file.seek(SeekFrom::Current(chunk.data_size as i64))
+                .context("failed to skip chunk")?;
+
+            if chunk.kind == CHUNK_RAW || chunk.kind == CHUNK_FILL {
+                match ranges.last_mut() {
+                    Some(last) if last.end == offset => last.end += chunk.len,
+                    _ => ranges.push(bmap::Range {
+                        start:    offset,
+                        end:      offset + chunk.len,
+                        checksum: None,
+                    }),
+                }
+            }
+            offset += chunk.len;
+        }
+
+        if offset != header.size() {
+            return Err(eyre!("Sparse image chunks don't add up to its size"));
+        }
+
+        Ok(bmap::BlockMap {
+            image_size: header.size(),
+            checksum_type: bmap::ChecksumType::Sha256,
+            ranges,
+        })
+    }
+
+    fn next_chunk(&mut self) -> io::Result<bool> {
+        while self.left == 0 {
+            if self.chunks_left == 0 {
+                return Ok(false);
+            }
+            self.chunks_left -= 1;
+
+            let chunk = self
+                .header
+                .read_chunk(&mut self.file)
+                .map_err(io::Error::other)?;
+            self.kind = chunk.kind;
+            self.left = chunk.len;
+            self.filled = 0;
+
+            match chunk.kind {
+                CHUNK_FILL => self.file.read_exact(&mut self.fill)?,
+                CHUNK_CRC32 => {
+                    let mut buf = [0u8; 4];
+                    self.file.read_exact(&mut buf)?;
+                    if self.crc.clone().finalize() != le_u32(&buf, 0) {
+                        return Err(io::Error::other("sparse image CRC32 mismatch"));
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || !self.next_chunk()? {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(self.left);
+        match self.kind {
+            CHUNK_RAW => self.file.read_exact(&mut buf[..len])?,
+            CHUNK_FILL => {
+                for byte in &mut buf[..len] {
+                    *byte = self.fill[self.filled % 4];
+                    self.filled += 1;
+                }
+            },
+            _ => buf[..len].fill(0),
+        }
+
+        self.crc.update(&buf[..len]);
+        self.left -= len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    const BLOCK: usize = 1024;
+
+    fn chunk(image: &mut Vec<u8>, kind: u16, blocks: u32, data: &[u8]) {
+        image.extend(kind.to_le_bytes());
+        image.extend([0u8; 2]);
+        image.extend(blocks.to_le_bytes());
+        image.extend(((CHUNK_HEADER_SIZE + data.len()) as u32).to_le_bytes());
+        image.extend(data);
+    }
+
+    /// Raw block, hole and filled block, closed by a checksum of the whole.
+    fn image() -> (Vec<u8>, Vec<u8>) {
+        let mut expanded = vec![0x5Au8; BLOCK];
+        expanded.extend([0u8; BLOCK]);
+        expanded.extend([1u8, 2, 3, 4].repeat(BLOCK / 4));
+
+        let mut image = MAGIC.to_le_bytes().to_vec();
+        image.extend(MAJOR_VERSION.to_le_bytes());
+        image.extend(0u16.to_le_bytes());
+        image.extend((FILE_HEADER_SIZE as u16).to_le_bytes());
+        image.extend((CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        image.extend((BLOCK as u32).to_le_bytes());
+        image.extend(3u32.to_le_bytes());
+        image.extend(4u32.to_le_bytes());
+        image.extend(0u32.to_le_bytes());
+
+        chunk(&mut image, CHUNK_RAW, 1, &expanded[..BLOCK]);
+        chunk(&mut image, CHUNK_DONT_CARE, 1, &[]);
+        chunk(&mut image, CHUNK_FILL, 1, &[1, 2, 3, 4]);
+        chunk(
+            &mut image,
+            CHUNK_CRC32,
+            0,
+            &crc32fast::hash(&expanded).to_le_bytes(),
+        );
+        (image, expanded)
+    }
+
+    #[test]
+    fn expands_chunks() {
+        let (image, expanded) = image();
+        let file = temp_file(&image);
+        let mut data = Vec::new();
+        SparseReader::open(file.path())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, expanded);
+
+        let map = SparseReader::block_map(file.path()).unwrap();
+        assert_eq!(map.image_size, 3 * BLOCK);
+        let ranges: Vec<_> = map
+            .ranges
+            .iter()
+            .map(|range| (range.start, range.end))
+            .collect();
+        assert_eq!(ranges, [(0, BLOCK), (2 * BLOCK, 3 * BLOCK)]);
+    }
+
+    #[test]
+    fn rejects_truncated_image() {
+        let (image, _) = image();
+        let truncated = temp_file(&image[..FILE_HEADER_SIZE + CHUNK_HEADER_SIZE + BLOCK / 2]);
+        let mut data = Vec::new();
+        assert!(
+            SparseReader::open(truncated.path())
+                .unwrap()
+                .read_to_end(&mut data)
+                .is_err()
+        );
+        assert!(SparseReader::block_map(truncated.path()).is_err());
+        assert!(SparseReader::open(temp_file(&image[..20]).path()).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let (image, _) = image();
+        let chunk = FILE_HEADER_SIZE;
+        let fields: [(usize, &[u8]); 5] = [
+            (10, &4u16.to_le_bytes()),
+            (12, &1023u32.to_le_bytes()),
+            (chunk, &0xCAC9u16.to_le_bytes()),
+            (chunk + 4, &2u32.to_le_bytes()),
+            (chunk + 8, &4u32.to_le_bytes()),
+        ];
+        for (at, value) in fields {
+            let mut bad = image.clone();
+            bad[at..at + value.len()].copy_from_slice(value);
+            let file = temp_file(&bad);
+            let mut data = Vec::new();
+            let read = SparseReader::open(file.path()).and_then(|mut reader| {
+                reader.read_to_end(&mut data)?;
+                Ok(())
+            });
+            assert!(read.is_err());
+            assert!(SparseReader::block_map(file.path()).is_err());
+        }
+    }
+}
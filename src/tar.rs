@@ -0,0 +1,214 @@
+use crate::reader::Stream;
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::fmt;
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_REGULAR: [u8; 3] = [b'0', b'\0', b'7'];
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_PAX: u8 = b'x';
+
+/// Regular file stored in the archive, `offset` being where its data starts in the stream.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name:   String,
+    pub size:   usize,
+    pub offset: usize,
+}
+
+impl fmt::Display for Member {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{name} ({size:.1} MiB)",
+            name = self.name,
+            size = self.size as f64 / (1024 * 1024) as f64
+        )
+    }
+}
+
+fn text(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parses numeric fields, either octal text or base-256 used by GNU tar for large values.
+fn number(field: &[u8]) -> Result<usize> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..]
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 8) | b as usize));
+    }
+    let digits = text(field);
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, 8).with_context(|| format!("invalid tar number {digits:?}"))
+}
+
+/// Extracts `path` and `size` records of a pax extended header.
+fn pax_records(data: &[u8]) -> (Option<String>, Option<usize>) {
+    let mut path = None;
+    let mut size = None;
+    for record in String::from_utf8_lossy(data).lines() {
+        let Some((_, record)) = record.split_once(' ') else {
+            continue;
+        };
+        match record.split_once('=') {
+            Some(("path", value)) => path = Some(value.to_string()),
+            Some(("size", value)) => size = value.parse().ok(),
+            _ => (),
+        }
+    }
+    (path, size)
+}
+
+/// Streams through the archive headers, listing its regular files. Stops at the first one `last`
+/// accepts, sparing the decompression of the rest of the archive.
+pub fn members(reader: &mut dyn Stream, last: impl Fn(&str) -> bool) -> Result<Vec<Member>> {
+    let mut members = Vec::new();
+    let mut offset = 0;
+    let mut long_name: Option<String> = None;
+    let mut long_size: Option<usize> = None;
+    let mut header = [0u8; BLOCK_SIZE];
+
+    loop {
+        reader
+            .read_exact(&mut header)
+            .context("failed to read tar header")?;
+        offset += BLOCK_SIZE;
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let checksum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as usize)
+            .sum();
+        if number(&header[148..156])? != checksum {
+            return Err(eyre!("tar header checksum mismatch at offset {offset}"));
+        }
+
+        let kind = header[156];
+        let size = long_size
+            .take()
+            .map_or_else(|| number(&header[124..136]), Ok)?;
+        let mut name = text(&header[..100]);
+        if &header[257..263] == b"ustar\0" && header[345] != 0 {
+            name = format!("{}/{name}", text(&header[345..500]));
+        }
+        let name = long_name.take().unwrap_or(name);
+        let padded = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        match kind {
+            TYPE_GNU_LONG_NAME | TYPE_PAX => {
+                let mut data = vec![0u8; padded];
+                reader
+                    .read_exact(&mut data)
+                    .context("failed to read tar extended header")?;
+                data.truncate(size);
+                if kind == TYPE_GNU_LONG_NAME {
+                    long_name = Some(text(&data));
+                } else {
+                    (long_name, long_size) = pax_records(&data);
+                }
+            },
+            _ => {
+                if TYPE_REGULAR.contains(&kind) {
+                    trace!("tar member {name:?}, {size} bytes at {offset}");
+                    let found = last(&name);
+                    members.push(Member { name, size, offset });
+                    if found {
+                        break;
+                    }
+                }
+                reader.skip(padded).context("failed to skip tar member")?;
+            },
+        }
+        offset += padded;
+    }
+
+    debug!("tar archive with {} files", members.len());
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+    use std::{fs, io::BufReader};
+
+    fn header(name: &str, size: usize, kind: u8) -> [u8; BLOCK_SIZE] {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[124..136].copy_from_slice(format!("{size:011o}\0").as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].fill(b' ');
+        let checksum: usize = header.iter().map(|&b| b as usize).sum();
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+        header
+    }
+
+    /// Archive of a directory, an image and a file with a name too long for the header.
+    fn archive() -> Vec<u8> {
+        let long = format!("{}.img", "x".repeat(120));
+        let mut archive = header("dir/", 0, b'5').to_vec();
+        archive.extend(header("dir/disk.img", 1000, b'0'));
+        archive.extend([0x5Au8; 1024]);
+        archive.extend(header("././@LongLink", long.len() + 1, TYPE_GNU_LONG_NAME));
+        archive.extend(format!("{long}\0").as_bytes());
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        archive.extend(header(&long[..100], 512, b'0'));
+        archive.extend([0u8; 512]);
+        archive.extend([0u8; 2 * BLOCK_SIZE]);
+        archive
+    }
+
+    fn members(data: &[u8], last: impl Fn(&str) -> bool) -> Result<Vec<Member>> {
+        let file = temp_file(data);
+        let mut reader = BufReader::new(fs::File::open(file.path()).unwrap());
+        super::members(&mut reader, last)
+    }
+
+    #[test]
+    fn lists_regular_files() {
+        let found = members(&archive(), |_| false).unwrap();
+        let found: Vec<_> = found
+            .iter()
+            .map(|member| (member.name.as_str(), member.size, member.offset))
+            .collect();
+        let long = format!("{}.img", "x".repeat(120));
+        assert_eq!(
+            found,
+            [("dir/disk.img", 1000, 1024), (long.as_str(), 512, 3584)]
+        );
+    }
+
+    #[test]
+    fn stops_at_first_accepted_member() {
+        // Whatever follows the image is not even looked at
+        let mut archive = archive();
+        archive.truncate(3 * BLOCK_SIZE);
+        let found = members(&archive, |name| name.ends_with(".img")).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "dir/disk.img");
+    }
+
+    #[test]
+    fn rejects_truncated_archive() {
+        let archive = archive();
+        assert!(members(&archive[..archive.len() - 2 * BLOCK_SIZE], |_| false).is_err());
+        assert!(members(&archive[..100], |_| false).is_err());
+
+        let mut corrupt = archive.clone();
+        corrupt[BLOCK_SIZE] ^= 1;
+        assert!(members(&corrupt, |_| false).is_err());
+    }
+}
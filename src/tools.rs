@@ -1,5 +1,46 @@
+use std::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
 pub const BUF_SIZE: usize = 1024 * 1024;
 pub const PAGE_SIZE: usize = 4096;
+
+/// Signals asking to stop, caught while writing.
+const INTERRUPTS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupted(_signal: libc::c_int) { CANCELLED.store(true, Ordering::Relaxed); }
+
+/// Makes `^C` cancel the write instead of killing the process right away, until the returned
+/// guard is dropped. Pressing it again kills the process anyway.
+pub fn catch_interrupts() -> Interrupts {
+    for signal in INTERRUPTS {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = interrupted as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESETHAND;
+            libc::sigaction(signal, &action, ptr::null_mut());
+        }
+    }
+    Interrupts
+}
+
+/// Interrupts being caught, `^C` kills the process again once dropped.
+pub struct Interrupts;
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        for signal in INTERRUPTS {
+            unsafe { libc::signal(signal, libc::SIG_DFL) };
+        }
+    }
+}
+
+/// User asked to stop, by `^C` or alike, since [`catch_interrupts`].
+pub fn cancelled() -> bool { CANCELLED.load(Ordering::Relaxed) }
+
 pub fn countdown(seconds: u64, dev: &str) {
     let msg = format!("Will start overwriting {dev} in");
     let bar = indicatif::ProgressBar::new(seconds)
@@ -17,33 +58,82 @@ pub fn countdown(seconds: u64, dev: &str) {
     bar.finish_and_clear();
 }
 
-pub fn eyre_unroll(e: color_eyre::Report) -> String {
+pub fn eyre_unroll(e: &color_eyre::Report) -> String {
     e.chain()
         .map(|e| e.to_string())
         .collect::<Vec<_>>()
         .join(": ")
 }
 
+/// Little-endian integer at byte `at` of an on-disk structure.
+pub fn le_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().unwrap())
+}
+
+pub fn le_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+pub fn le_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Big-endian integer at byte `at` of an on-disk structure.
+pub fn be_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+pub fn be_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// File holding `data`, for tests of the image readers.
+#[cfg(test)]
+pub fn temp_file(data: &[u8]) -> tempfile::NamedTempFile {
+    use std::io::Write;
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(data).unwrap();
+    file
+}
+
+/// Buffer starting at a multiple of given alignment, as needed for `O_DIRECT`.
 pub struct AlignedBuffer {
-    buf:        Box<[u8; BUF_SIZE + PAGE_SIZE]>,
-    page_shift: usize,
+    buf:        Box<[u8]>,
+    shift:      usize,
+    size:       usize,
     pub used:   usize,
+    pub offset: usize,
+    /// Used part holds only zeroes, set when writing sparse.
+    pub zero:   bool,
 }
 
 impl AlignedBuffer {
-    pub fn new() -> AlignedBuffer {
-        let buf = Box::new([0u8; BUF_SIZE + PAGE_SIZE]);
-        let page_shift = (PAGE_SIZE - ((buf.as_ptr() as usize) & (PAGE_SIZE - 1))) % PAGE_SIZE;
+    pub fn new() -> AlignedBuffer { Self::with_layout(BUF_SIZE, PAGE_SIZE) }
+
+    /// Buffer of `size` bytes, `align` being a power of two.
+    pub fn with_layout(size: usize, align: usize) -> AlignedBuffer {
+        let buf = vec![0u8; size + align].into_boxed_slice();
+        let shift = (align - ((buf.as_ptr() as usize) & (align - 1))) % align;
         let used = 0;
+        let offset = 0;
+        let zero = false;
 
         Self {
             buf,
-            page_shift,
+            shift,
+            size,
             used,
+            offset,
+            zero,
         }
     }
 
+    pub fn size(&self) -> usize { self.size }
+
     pub fn get_aligned_buf(&mut self) -> &mut [u8] {
-        &mut self.buf[self.page_shift..self.page_shift + BUF_SIZE]
+        &mut self.buf[self.shift..self.shift + self.size]
     }
+
+    pub fn aligned(&self) -> &[u8] { &self.buf[self.shift..self.shift + self.size] }
 }
@@ -1,30 +1,112 @@
-use color_eyre::eyre::{Result, eyre};
-use dialoguer::{Select, theme::ColorfulTheme};
+use crate::{cli, error::Error, mounts, tools::BUF_SIZE};
+use color_eyre::eyre::{Context, Result, eyre};
+use dialoguer::{MultiSelect, theme::ColorfulTheme};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{ffi::OsStr, fmt, fs, path};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs, io, path,
+};
 
 const GIB: u64 = 1024 * 1024 * 1024;
 const GB: u64 = 1000 * 1000 * 1000;
 
+/// Largest transfer unit taken from the device, bigger ones are not to be trusted.
+const MAX_IO_UNIT: usize = 8 * BUF_SIZE;
+
+/// Serial numbers of devices never to be written, one per line, `#` starting a comment.
+const DENYLIST: &str = "/etc/image_writer_rs/denylist";
+
+/// How the device is attached, telling USB sticks and SD cards from internal disks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Usb,
+    Mmc,
+    Nvme,
+    Other,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Usb => "USB",
+            Transport::Mmc => "SD/MMC",
+            Transport::Nvme => "NVMe",
+            Transport::Other => "other",
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Device {
-    pub dev:    path::PathBuf,
-    pub model:  String,
-    pub vendor: String,
-    pub size:   usize,
+    pub dev:                 path::PathBuf,
+    pub model:               String,
+    pub vendor:              String,
+    pub serial:              String,
+    pub size:                usize,
+    pub transport:           Transport,
+    /// Smallest unit the device can be written in, 4096 on 4Kn devices.
+    pub logical_block_size:  usize,
+    /// Unit the device writes internally, anything smaller is read-modify-write.
+    pub physical_block_size: usize,
+    /// Preferred transfer size, 0 if not reported.
+    pub optimal_io_size:     usize,
+    /// Largest single request, larger transfers are split by the kernel.
+    pub max_io_size:         usize,
+    /// Largest write-zeroes request, 0 if the device can't zero without writing data.
+    pub write_zeroes_size:   usize,
+    /// Discarded blocks read back as zeroes, reported by older kernels only.
+    pub discard_zeroes:      bool,
+}
+
+impl Device {
+    /// Transfer size suiting the device: about `BUF_SIZE`, in whole preferred transfers.
+    pub fn io_size(&self) -> usize {
+        let unit = [self.optimal_io_size, self.max_io_size]
+            .into_iter()
+            // Some USB bridges report nonsense like 32 MiB
+            .find(|&unit| unit > 0 && unit <= MAX_IO_UNIT)
+            .unwrap_or(0)
+            .next_multiple_of(self.physical_block_size)
+            .max(self.physical_block_size);
+        BUF_SIZE.next_multiple_of(unit)
+    }
+}
+
+#[cfg(test)]
+impl Device {
+    /// Device of `size` bytes with `sector` byte sectors and no transfer size or zeroing
+    /// capabilities, for tests.
+    pub fn with_geometry(size: usize, sector: usize) -> Self {
+        Self {
+            dev: path::PathBuf::from("/dev/null"),
+            model: String::new(),
+            vendor: String::new(),
+            serial: String::new(),
+            size,
+            transport: Transport::Usb,
+            logical_block_size: sector,
+            physical_block_size: sector,
+            optimal_io_size: 0,
+            max_io_size: 0,
+            write_zeroes_size: 0,
+            discard_zeroes: false,
+        }
+    }
 }
 
 impl fmt::Display for Device {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(
             format!(
-                "{dev}: {vendor} {model} ({sizei:.1} GiB / {size:.1} GB)",
+                "{dev}: {vendor} {model} [{serial}] ({sizei:.1} GiB / {size:.1} GB, {transport})",
                 dev = self.dev.to_string_lossy(),
                 vendor = self.vendor,
                 model = self.model,
+                serial = self.serial,
                 size = self.size as f64 / GB as f64,
                 sizei = self.size as f64 / GIB as f64,
+                transport = self.transport,
             )
             .as_str(),
         )
@@ -44,86 +126,294 @@ fn get_int(dev: &path::Path, val: &str, radix: u32) -> Result<u64> {
     Ok(u64::from_str_radix(&dat, radix)?)
 }
 
+/// Sysfs directories of the hardware behind the block device, from the device itself up to
+/// the bus it hangs on.
+fn get_parents(sys_path: &path::Path) -> Vec<path::PathBuf> {
+    let Ok(device) = fs::canonicalize(sys_path.join("device")) else {
+        return Vec::new();
+    };
+    device
+        .ancestors()
+        .take_while(|dir| dir.starts_with("/sys/devices"))
+        .map(path::Path::to_path_buf)
+        .collect()
+}
+
+/// Looks for the serial number on the device itself or on any of its parents, as USB mass
+/// storage has it on the USB device.
+fn get_serial(sys_path: &path::Path) -> String {
+    get_parents(sys_path)
+        .iter()
+        .find_map(|dir| get_str(dir, "serial").ok())
+        .unwrap_or_default()
+}
+
+fn get_subsystem(dir: &path::Path) -> Option<String> {
+    let subsystem = fs::canonicalize(dir.join("subsystem")).ok()?;
+    Some(subsystem.file_name()?.to_string_lossy().into_owned())
+}
+
+/// Finds the nearest parent on a known bus. SCSI is skipped over, as both USB sticks and
+/// SATA disks show up as SCSI devices.
+fn get_transport(sys_path: &path::Path) -> Transport {
+    get_parents(sys_path)
+        .iter()
+        .find_map(|dir| match get_subsystem(dir)?.as_str() {
+            "usb" => Some(Transport::Usb),
+            "mmc" => Some(Transport::Mmc),
+            "nvme" => Some(Transport::Nvme),
+            _ => None,
+        })
+        .unwrap_or(Transport::Other)
+}
+
+/// Tells whether the block device looks like media to be written: marked removable, attached
+/// by USB, an SD card or behind a port marked external. Write-protected ones are left out.
+fn is_candidate(sys_path: &path::Path) -> bool {
+    let name = sys_path.file_name().unwrap_or_default().to_string_lossy();
+    // Optical drives are removable too
+    if name.starts_with("sr") || get_int(sys_path, "ro", 10).unwrap_or(1) != 0 {
+        return false;
+    }
+    if get_int(sys_path, "removable", 10).unwrap_or(0) == 1 {
+        return true;
+    }
+    match get_transport(sys_path) {
+        Transport::Usb => true,
+        // Built-in eMMC reports as MMC
+        Transport::Mmc => get_str(sys_path, "device/type").is_ok_and(|kind| kind == "SD"),
+        Transport::Nvme | Transport::Other => get_parents(sys_path)
+            .iter()
+            .any(|dir| get_str(dir, "removable").is_ok_and(|state| state == "removable")),
+    }
+}
+
+/// Devices not to be lost by accident: those holding the running system and those denylisted
+/// by serial number.
+pub struct Protected {
+    system:  Vec<OsString>,
+    serials: Vec<String>,
+}
+
+impl Protected {
+    pub fn load() -> Result<Self> {
+        let system = mounts::system_disks().context("failed to find system disks")?;
+
+        let mut serials = cli::args().deny_serial.clone();
+        match fs::read_to_string(DENYLIST) {
+            Ok(data) => serials.extend(
+                data.lines()
+                    .filter_map(|line| line.split('#').next())
+                    .map(str::trim)
+                    .filter(|serial| !serial.is_empty())
+                    .map(String::from),
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {DENYLIST}")),
+        }
+
+        Ok(Self { system, serials })
+    }
+
+    /// Fails for protected devices. The system disk passes only when `named` by `--device` or
+    /// `--serial` and explicitly allowed, never when devices are picked for the user.
+    pub fn check(&self, device: &Device, named: bool) -> Result<()> {
+        if !device.serial.is_empty() && self.serials.contains(&device.serial) {
+            return Err(eyre!(
+                "serial {serial:?} is denylisted",
+                serial = device.serial
+            ));
+        }
+
+        let name = device.dev.file_name().unwrap_or_default();
+        if self.system.iter().any(|disk| disk == name) {
+            if !named {
+                return Err(eyre!("device holds the running system"));
+            }
+            if !cli::args().allow_system_disk {
+                return Err(eyre!(
+                    "device holds the running system, use --allow-system-disk to write it anyway"
+                ));
+            }
+            warn!(
+                "!!! {dev:?} HOLDS THE RUNNING SYSTEM, overwriting it will break this machine !!!",
+                dev = device.dev
+            );
+        }
+        Ok(())
+    }
+}
+
 pub fn check_device(sys: &path::Path) -> Result<Device> {
     let base_name = sys.file_name().and_then(OsStr::to_str).unwrap();
     let sys_path = path::Path::new("/sys/block").join(base_name);
-    let vendor = get_str(&sys_path, "device/vendor")?;
-    let model = get_str(&sys_path, "device/model")?;
+    // SD cards only have their type and name, NVMe drives lack the vendor
+    let vendor = get_str(&sys_path, "device/vendor")
+        .or_else(|_| get_str(&sys_path, "device/type"))
+        .unwrap_or_default();
+    let model = get_str(&sys_path, "device/model")
+        .or_else(|_| get_str(&sys_path, "device/name"))
+        .unwrap_or_default();
     let size = get_int(&sys_path, "size", 10)? as usize * 512;
     if size == 0 {
         return Err(eyre!("Probably an empty card reader"));
     }
 
+    let queue = |attr: &str, default: u64| {
+        get_int(&sys_path, &format!("queue/{attr}"), 10).unwrap_or(default) as usize
+    };
     let dev = Device {
         dev: sys.to_path_buf(),
         model,
         vendor,
+        serial: get_serial(&sys_path),
         size,
+        transport: get_transport(&sys_path),
+        logical_block_size: queue("logical_block_size", 512),
+        physical_block_size: queue("physical_block_size", 512),
+        optimal_io_size: queue("optimal_io_size", 0),
+        max_io_size: queue("max_sectors_kb", 0) * 1024,
+        write_zeroes_size: queue("write_zeroes_max_bytes", 0),
+        discard_zeroes: queue("discard_zeroes_data", 0) == 1 && queue("discard_max_bytes", 0) > 0,
     };
+    debug!(
+        "{dev:?}: {logical}/{physical} byte sectors, optimal I/O {optimal}, max I/O {max}",
+        dev = dev.dev,
+        logical = dev.logical_block_size,
+        physical = dev.physical_block_size,
+        optimal = dev.optimal_io_size,
+        max = dev.max_io_size
+    );
 
     Ok(dev)
 }
 
-pub fn detect_pendrives() -> Result<Device> {
+/// Detaches the USB device holding the block device from the bus, like `udisksctl power-off`
+/// does. Hubs supporting it power the port down too.
+pub fn power_off(device: &Device) -> Result<()> {
+    let name = device.dev.file_name().unwrap_or_default();
+    // Interfaces are USB too, only the device itself can be removed
+    let usb = get_parents(&path::Path::new("/sys/block").join(name))
+        .into_iter()
+        .find(|dir| dir.join("remove").exists() && get_subsystem(dir).as_deref() == Some("usb"))
+        .ok_or_else(|| eyre!("not a USB device"))?;
+    fs::write(usb.join("remove"), "1")
+        .with_context(|| format!("failed to remove USB device {usb:?}"))
+}
+
+/// Describes a device given explicitly, by its `/dev` node or any symlink to it.
+pub fn open_device(dev: &path::Path) -> Result<Device> {
+    let path = fs::canonicalize(dev)?;
+    let device = check_device(&path)?;
+    Protected::load()?.check(&device, true)?;
+    Ok(device)
+}
+
+/// Lists removable media present, leaving out empty card reader slots.
+pub fn scan() -> Result<Vec<Device>> {
     let mut devices = Vec::new();
-    for entry in fs::read_dir("/dev/disk/by-id")? {
-        match entry {
-            Ok(entry) => {
-                let dev = entry.file_name().to_string_lossy().to_string();
-                if dev.starts_with("usb-") && dev.ends_with("0:0") {
-                    match fs::canonicalize(entry.path()) {
-                        Ok(path) => {
-                            if !path
-                                .file_name()
-                                .unwrap()
-                                .to_string_lossy()
-                                .starts_with("sr")
-                            {
-                                match check_device(&path) {
-                                    Ok(device) => {
-                                        devices.push(device);
-                                    },
-                                    Err(e) => {
-                                        debug!("Skipped device {path:?}: {e}");
-                                    },
-                                }
-                            }
-                        },
-
-                        Err(e) => {
-                            error!("Failed to dereference device: {e}");
-                        },
-                    }
-                }
-            },
+    for entry in fs::read_dir("/sys/block")? {
+        let entry = match entry {
+            Ok(entry) => entry,
             Err(e) => {
                 debug!("Failed to iterate over entry {e}");
+                continue;
             },
+        };
+        if !is_candidate(&entry.path()) {
+            trace!("Not removable media: {name:?}", name = entry.file_name());
+            continue;
+        }
+
+        let path = path::Path::new("/dev").join(entry.file_name());
+        match check_device(&path) {
+            Ok(device) => devices.push(device),
+            // Card readers have a block device for each slot, even the empty ones
+            Err(e) => debug!("Skipped device {path:?}: {e}"),
         }
     }
 
+    devices.sort_by(|dev1, dev2| dev1.dev.cmp(&dev2.dev));
+    Ok(devices)
+}
+
+pub fn detect_pendrives() -> Result<Vec<Device>> {
+    let args = cli::args();
+    let protected = Protected::load()?;
+    let mut devices = scan()?;
+    devices.retain(
+        |device| match protected.check(device, args.serial.contains(&device.serial)) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Skipped {dev:?}: {e}", dev = device.dev);
+                false
+            },
+        },
+    );
+
     if devices.is_empty() {
         return Err(eyre!("No devices found"));
     }
 
-    devices.sort_by(|dev1, dev2| dev1.dev.cmp(&dev2.dev));
-    devices.dedup_by(|dev1, dev2| dev1.dev.eq(&dev2.dev));
-
-    let device = if devices.len() > 1 {
-        info!("Multiple devices detected");
-        let selection = Select::with_theme(&ColorfulTheme::default())
-            .default(0)
-            .with_prompt("Select device to overwrite [q to abort]:")
-            .items(&devices)
-            .interact_opt()?;
-        if let Some(selection) = selection {
-            &devices[selection]
-        } else {
-            return Err(eyre!("No device selected"));
+    if !args.serial.is_empty() {
+        let mut chosen = Vec::with_capacity(args.serial.len());
+        for serial in &args.serial {
+            let mut matching = devices.iter().filter(|device| device.serial.eq(serial));
+            match (matching.next(), matching.next()) {
+                (Some(device), None) => chosen.push(device.clone()),
+                (None, _) => return Err(eyre!("No device with serial {serial:?} found")),
+                (Some(_), Some(_)) => {
+                    return Err(eyre!(
+                        "Several devices share serial {serial:?}, pick them with --device"
+                    ));
+                },
+            }
         }
-    } else {
-        &devices[0]
-    };
+        return Ok(chosen);
+    }
+
+    if devices.len() == 1 || args.all {
+        return Ok(devices);
+    }
 
-    Ok(device.clone())
+    if args.non_interactive {
+        return Err(eyre!(
+            "{count} devices detected, pick them with --device, --serial or --all",
+            count = devices.len()
+        ));
+    }
+    info!("Multiple devices detected");
+    let selection = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select devices to overwrite [space to toggle, q to abort]:")
+        .items(&devices)
+        .interact_opt()?;
+    match selection {
+        Some(selection) if !selection.is_empty() => {
+            Ok(selection.into_iter().map(|n| devices[n].clone()).collect())
+        },
+        _ => Err(Error::Aborted.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_io_size() {
+        let mut device = Device::with_geometry(1 << 30, 512);
+        assert_eq!(device.io_size(), BUF_SIZE);
+
+        device.physical_block_size = 4096;
+        device.optimal_io_size = 3 << 20;
+        assert_eq!(device.io_size(), 3 << 20);
+
+        // Bogus preferred size, the largest request is used instead
+        device.optimal_io_size = 32 << 20;
+        device.max_io_size = 1280 * 1024;
+        assert_eq!(device.io_size(), 1280 * 1024);
+
+        device.max_io_size = 0;
+        assert_eq!(device.io_size(), BUF_SIZE);
+    }
 }
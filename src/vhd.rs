@@ -0,0 +1,309 @@
+use crate::tools::{be_u32, be_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs, io,
+    io::{Read, Seek, SeekFrom},
+    path,
+};
+
+const FOOTER_COOKIE: &[u8; 8] = b"conectix";
+const SPARSE_COOKIE: &[u8; 8] = b"cxsparse";
+const SECTOR: u64 = 512;
+/// Largest block size accepted, far above the 2 MiB default of all known tools.
+const MAX_BLOCK_SIZE: u64 = 256 << 20;
+
+const TYPE_FIXED: u32 = 2;
+const TYPE_DYNAMIC: u32 = 3;
+const TYPE_DIFFERENCING: u32 = 4;
+
+const BAT_UNUSED: u32 = u32::MAX;
+
+/// One's complement of the byte sum, skipping the checksum field itself.
+fn checksum(buf: &[u8], field: usize) -> u32 {
+    let sum = buf
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(field..field + 4).contains(i))
+        .fold(0u32, |sum, (_, &b)| sum.wrapping_add(b as u32));
+    !sum
+}
+
+/// Guest-visible contents of a fixed or dynamic VHD image, presented as a raw stream.
+pub struct VhdReader {
+    file:       fs::File,
+    size:       u64,
+    bat:        Option<Vec<u32>>,
+    block_size: u64,
+    block:      Vec<u8>,
+    block_no:   Option<u64>,
+    pos:        u64,
+}
+
+impl VhdReader {
+    pub fn open(path: &path::Path) -> Result<Self> {
+        let mut file = fs::File::open(path).context("failed to open file")?;
+        let file_size = file.metadata().context("failed to stat file")?.len();
+        if file_size < SECTOR {
+            return Err(eyre!("File too short for VHD"));
+        }
+
+        // Footer is 511 bytes in images made by old Virtual PC versions
+        let mut footer = [0u8; SECTOR as usize];
+        file.seek(SeekFrom::Start(file_size - SECTOR))
+            .context("failed to seek to footer")?;
+        file.read_exact(&mut footer)
+            .context("failed to read footer")?;
+        if !footer.starts_with(FOOTER_COOKIE) {
+            footer.copy_within(1.., 0);
+            if !footer.starts_with(FOOTER_COOKIE) {
+                // Dynamic disks carry a footer copy at the start
+                file.rewind().context("failed to rewind file")?;
+                file.read_exact(&mut footer)
+                    .context("failed to read footer copy")?;
+                if !footer.starts_with(FOOTER_COOKIE) {
+                    return Err(eyre!("Not a VHD image"));
+                }
+                warn!("VHD footer missing at the end, using the copy at the start");
+            }
+        }
+
+        if checksum(&footer[..85], 64) != be_u32(&footer, 64) {
+            return Err(eyre!("VHD footer checksum mismatch"));
+        }
+
+        let size = be_u64(&footer, 48);
+        let disk_type = be_u32(&footer, 60);
+
+        match disk_type {
+            TYPE_FIXED => {
+                if size > file_size - SECTOR + 1 {
+                    return Err(eyre!("Fixed VHD shorter than its declared size"));
+                }
+                debug!("Fixed VHD, {size} bytes");
+                file.rewind().context("failed to rewind file")?;
+                Ok(Self {
+                    file,
+                    size,
+                    bat: None,
+                    block_size: 0,
+                    block: Vec::new(),
+                    block_no: None,
+                    pos: 0,
+                })
+            },
+            TYPE_DYNAMIC => {
+                let mut header = [0u8; 1024];
+                file.seek(SeekFrom::Start(be_u64(&footer, 16)))
+                    .context("failed to seek to dynamic header")?;
+                file.read_exact(&mut header)
+                    .context("failed to read dynamic header")?;
+                if !header.starts_with(SPARSE_COOKIE) {
+                    return Err(eyre!("Invalid VHD dynamic header"));
+                }
+                if checksum(&header, 36) != be_u32(&header, 36) {
+                    return Err(eyre!("VHD dynamic header checksum mismatch"));
+                }
+
+                let bat_offset = be_u64(&header, 16);
+                let entries = be_u32(&header, 28) as u64;
+                let block_size = be_u32(&header, 32) as u64;
+                if !(SECTOR..=MAX_BLOCK_SIZE).contains(&block_size) || !block_size.is_power_of_two()
+                {
+                    return Err(eyre!("Invalid VHD block size {block_size}"));
+                }
+                // Only the entries covering the virtual size are ever looked at
+                let needed = size.div_ceil(block_size);
+                if entries < needed {
+                    return Err(eyre!("VHD block allocation table too small"));
+                }
+                if bat_offset
+                    .checked_add(needed * 4)
+                    .is_none_or(|end| end > file_size)
+                {
+                    return Err(eyre!("VHD block allocation table lies outside of the file"));
+                }
+
+                let mut raw = vec![0u8; needed as usize * 4];
+                file.seek(SeekFrom::Start(bat_offset))
+                    .context("failed to seek to block allocation table")?;
+                file.read_exact(&mut raw)
+                    .context("failed to read block allocation table")?;
+                let bat = raw.chunks_exact(4).map(|entry| be_u32(entry, 0)).collect();
+
+                debug!("Dynamic VHD, {size} bytes, {block_size} bytes blocks");
+                Ok(Self {
+                    file,
+                    size,
+                    bat: Some(bat),
+                    block_size,
+                    block: vec![0u8; block_size as usize],
+                    block_no: None,
+                    pos: 0,
+                })
+            },
+            TYPE_DIFFERENCING => Err(eyre!(
+                "Differencing VHD images are not supported, merge it with its parent first"
+            )),
+            _ => Err(eyre!("Unknown VHD disk type {disk_type}")),
+        }
+    }
+
+    fn load_block(&mut self, block_no: u64) -> io::Result<()> {
+        if self.block_no == Some(block_no) {
+            return Ok(());
+        }
+        let Some(bat) = &self.bat else {
+            return Ok(());
+        };
+
+        let entry = bat[block_no as usize];
+        if entry == BAT_UNUSED {
+            self.block.fill(0);
+        } else {
+            let sectors = self.block_size / SECTOR;
+            let bitmap_len = sectors.div_ceil(8).next_multiple_of(SECTOR);
+            let mut bitmap = vec![0u8; bitmap_len as usize];
+
+            self.file.seek(SeekFrom::Start(entry as u64 * SECTOR))?;
+            self.file.read_exact(&mut bitmap)?;
+            self.file.read_exact(&mut self.block)?;
+
+            // Sectors never written in an allocated block read back as zeros
+            for (sector, data) in self.block.chunks_exact_mut(SECTOR as usize).enumerate() {
+                if bitmap[sector / 8] & (0x80 >> (sector % 8)) == 0 {
+                    data.fill(0);
+                }
+            }
+        }
+
+        self.block_no = Some(block_no);
+        Ok(())
+    }
+}
+
+impl Read for VhdReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let left = (self.size - self.pos) as usize;
+        let len = if self.bat.is_none() {
+            let len = buf.len().min(left);
+            self.file.read_exact(&mut buf[..len])?;
+            len
+        } else {
+            self.load_block(self.pos / self.block_size)?;
+            let in_block = (self.pos % self.block_size) as usize;
+            let len = buf.len().min(self.block.len() - in_block).min(left);
+            buf[..len].copy_from_slice(&self.block[in_block..in_block + len]);
+            len
+        };
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    fn footer(size: u64, disk_type: u32) -> Vec<u8> {
+        let mut footer = vec![0u8; SECTOR as usize];
+        footer[..8].copy_from_slice(FOOTER_COOKIE);
+        footer[16..24].copy_from_slice(&SECTOR.to_be_bytes());
+        footer[48..56].copy_from_slice(&size.to_be_bytes());
+        footer[60..64].copy_from_slice(&disk_type.to_be_bytes());
+        let sum = checksum(&footer[..85], 64);
+        footer[64..68].copy_from_slice(&sum.to_be_bytes());
+        footer
+    }
+
+    fn dynamic_header(bat_offset: u64, entries: u32, block_size: u32) -> Vec<u8> {
+        let mut header = vec![0u8; 1024];
+        header[..8].copy_from_slice(SPARSE_COOKIE);
+        header[16..24].copy_from_slice(&bat_offset.to_be_bytes());
+        header[28..32].copy_from_slice(&entries.to_be_bytes());
+        header[32..36].copy_from_slice(&block_size.to_be_bytes());
+        let sum = checksum(&header, 36);
+        header[36..40].copy_from_slice(&sum.to_be_bytes());
+        header
+    }
+
+    /// Dynamic VHD of two 1 KiB blocks. Only the first sector of the first block is written.
+    fn dynamic() -> Vec<u8> {
+        let mut image = footer(2048, TYPE_DYNAMIC);
+        image.extend(dynamic_header(1536, 2, 1024));
+
+        let mut bat = vec![0xFFu8; SECTOR as usize];
+        bat[..4].copy_from_slice(&4u32.to_be_bytes());
+        image.extend(bat);
+
+        let mut bitmap = vec![0u8; SECTOR as usize];
+        bitmap[0] = 0x80;
+        image.extend(bitmap);
+        image.extend([0x5Au8; 1024]);
+
+        image.extend(footer(2048, TYPE_DYNAMIC));
+        image
+    }
+
+    fn read(data: &[u8]) -> Result<Vec<u8>> {
+        let file = temp_file(data);
+        let mut data = Vec::new();
+        VhdReader::open(file.path())?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn reads_fixed_image() {
+        let mut image = vec![0x5Au8; 1024];
+        image.extend(footer(1024, TYPE_FIXED));
+        assert_eq!(read(&image).unwrap(), [0x5Au8; 1024]);
+    }
+
+    #[test]
+    fn reads_dynamic_image() {
+        let data = read(&dynamic()).unwrap();
+        assert_eq!(data.len(), 2048);
+        assert!(data[..512].iter().all(|&b| b == 0x5A));
+        assert!(data[512..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_image() {
+        let image = dynamic();
+        assert!(read(&image[..100]).is_err());
+        assert!(read(&image[..3000]).is_err());
+
+        let mut fixed = vec![0x5Au8; 512];
+        fixed.extend(footer(1024, TYPE_FIXED));
+        assert!(read(&fixed).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_dynamic_header() {
+        for (bat_offset, entries, block_size) in [
+            (1536, 2, 1536),
+            (1536, 2, 1 << 30),
+            (1 << 40, 2, 1024),
+            (u64::MAX, 2, 1024),
+            (1536, 1, 1024),
+        ] {
+            let mut image = dynamic();
+            image[512..1536].copy_from_slice(&dynamic_header(bat_offset, entries, block_size));
+            assert!(read(&image).is_err());
+        }
+
+        // Virtual size calling for a table far larger than the file
+        let mut image = dynamic();
+        let end = image.len() - 512;
+        image[end..].copy_from_slice(&footer(1 << 50, TYPE_DYNAMIC));
+        image[512..1536].copy_from_slice(&dynamic_header(1536, u32::MAX, 1024));
+        assert!(read(&image).is_err());
+    }
+}
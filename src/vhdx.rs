@@ -0,0 +1,396 @@
+use crate::tools::{le_u16, le_u32, le_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs, io,
+    io::{Read, Seek, SeekFrom},
+    path,
+};
+
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+
+const FILE_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8; 4] = b"head";
+const REGION_SIGNATURE: &[u8; 4] = b"regi";
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+
+const HEADER_OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+const REGION_OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+const HEADER_SIZE: usize = 4 * KB as usize;
+const REGION_SIZE: usize = 64 * KB as usize;
+
+// GUIDs in on-disk (mixed endian) byte order
+
+/// 2DC27766-F623-4200-9D64-115E9BFD4A08
+const REGION_BAT: [u8; 16] = [
+    0x66, 0x77, 0xC2, 0x2D, 0x23, 0xF6, 0x00, 0x42, 0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08,
+];
+/// 8B7CA206-4790-4B9A-B8FE-575F050F886E
+const REGION_METADATA: [u8; 16] = [
+    0x06, 0xA2, 0x7C, 0x8B, 0x90, 0x47, 0x9A, 0x4B, 0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E,
+];
+/// CAA16737-FA36-4D43-B3B6-33F0AA44E76B
+const META_FILE_PARAMETERS: [u8; 16] = [
+    0x37, 0x67, 0xA1, 0xCA, 0x36, 0xFA, 0x43, 0x4D, 0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B,
+];
+/// 2FA54224-CD1B-4876-B211-5DBED83BF4B8
+const META_DISK_SIZE: [u8; 16] = [
+    0x24, 0x42, 0xA5, 0x2F, 0x1B, 0xCD, 0x76, 0x48, 0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8,
+];
+/// 8141BF1D-A96F-4709-BA47-F233A8FAAB5F
+const META_LOGICAL_SECTOR: [u8; 16] = [
+    0x1D, 0xBF, 0x41, 0x81, 0x6F, 0xA9, 0x09, 0x47, 0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F,
+];
+
+const FILE_HAS_PARENT: u32 = 1 << 1;
+const METADATA_REQUIRED: u32 = 1 << 2;
+
+const BAT_STATE_MASK: u64 = 0x7;
+const BAT_OFFSET_MASK: u64 = !(MB - 1);
+const PAYLOAD_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_PARTIALLY_PRESENT: u64 = 7;
+
+/// CRC-32C (Castagnoli) of `buf` with the 4-byte checksum field at `field` taken as zero.
+fn crc32c(buf: &[u8], field: usize) -> u32 {
+    let mut crc = !0u32;
+    for (i, &byte) in buf.iter().enumerate() {
+        let byte = if (field..field + 4).contains(&i) {
+            0
+        } else {
+            byte
+        };
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82F6_3B78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn read_at(file: &mut fs::File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let file_len = file.metadata()?.len();
+    if offset
+        .checked_add(len as u64)
+        .is_none_or(|end| end > file_len)
+    {
+        return Err(eyre!(
+            "VHDX structure at {offset:#x} lies outside of the file"
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Guest-visible contents of a VHDX image, presented as a raw stream.
+pub struct VhdxReader {
+    file:       fs::File,
+    size:       u64,
+    bat:        Vec<u64>,
+    block_size: u64,
+    chunk:      u64,
+    block:      Vec<u8>,
+    block_no:   Option<u64>,
+    pos:        u64,
+}
+
+impl VhdxReader {
+    pub fn open(path: &path::Path) -> Result<Self> {
+        let mut file = fs::File::open(path).context("failed to open file")?;
+
+        let ident = read_at(&mut file, 0, 8).context("failed to read identifier")?;
+        if ident != FILE_SIGNATURE {
+            return Err(eyre!("Not a VHDX image"));
+        }
+
+        let mut header = None;
+        for offset in HEADER_OFFSETS {
+            let hdr = read_at(&mut file, offset, HEADER_SIZE).context("failed to read header")?;
+            if !hdr.starts_with(HEADER_SIGNATURE) || crc32c(&hdr, 4) != le_u32(&hdr, 4) {
+                warn!("Ignoring damaged VHDX header at {offset:#x}");
+                continue;
+            }
+            if header
+                .as_ref()
+                .is_none_or(|cur: &Vec<u8>| le_u64(&hdr, 8) > le_u64(cur, 8))
+            {
+                header = Some(hdr);
+            }
+        }
+        let header = header.ok_or_else(|| eyre!("No valid VHDX header"))?;
+
+        if le_u16(&header, 66) != 1 {
+            return Err(eyre!("Unsupported VHDX version {}", le_u16(&header, 66)));
+        }
+        if header[48..64].iter().any(|&b| b != 0) {
+            return Err(eyre!(
+                "VHDX log is not empty, open the image in Hyper-V or qemu-img to replay it"
+            ));
+        }
+
+        let mut regions = None;
+        for offset in REGION_OFFSETS {
+            let table =
+                read_at(&mut file, offset, REGION_SIZE).context("failed to read region table")?;
+            if table.starts_with(REGION_SIGNATURE) && crc32c(&table, 4) == le_u32(&table, 4) {
+                regions = Some(table);
+                break;
+            }
+            warn!("Ignoring damaged VHDX region table at {offset:#x}");
+        }
+        let regions = regions.ok_or_else(|| eyre!("No valid VHDX region table"))?;
+
+        let mut bat_region = None;
+        let mut metadata_region = None;
+        let count = le_u32(&regions, 8) as usize;
+        for entry in regions[16..].chunks_exact(32).take(count) {
+            let location = (le_u64(entry, 16), le_u32(entry, 24) as usize);
+            match entry[..16].try_into().unwrap() {
+                REGION_BAT => bat_region = Some(location),
+                REGION_METADATA => metadata_region = Some(location),
+                _ if le_u32(entry, 28) & 1 != 0 => {
+                    return Err(eyre!("Unknown required VHDX region"));
+                },
+                _ => (),
+            }
+        }
+        let (bat_offset, bat_len) = bat_region.ok_or_else(|| eyre!("VHDX lacks BAT region"))?;
+        let (meta_offset, meta_len) =
+            metadata_region.ok_or_else(|| eyre!("VHDX lacks metadata region"))?;
+
+        let metadata =
+            read_at(&mut file, meta_offset, meta_len).context("failed to read metadata")?;
+        if metadata.len() < 32 || !metadata.starts_with(METADATA_SIGNATURE) {
+            return Err(eyre!("Invalid VHDX metadata table"));
+        }
+        let item = |at: usize, len: usize| {
+            metadata
+                .get(at..at + len)
+                .ok_or_else(|| eyre!("VHDX metadata item lies outside of the metadata region"))
+        };
+
+        let mut block_size = None;
+        let mut size = None;
+        let mut sector_size = 512;
+        let count = le_u16(&metadata, 10) as usize;
+        for entry in metadata[32..].chunks_exact(32).take(count) {
+            let at = le_u32(entry, 16) as usize;
+            match entry[..16].try_into().unwrap() {
+                META_FILE_PARAMETERS => {
+                    let parameters = item(at, 8)?;
+                    if le_u32(parameters, 4) & FILE_HAS_PARENT != 0 {
+                        return Err(eyre!(
+                            "Differencing VHDX images are not supported, merge it with its parent \
+                             first"
+                        ));
+                    }
+                    block_size = Some(le_u32(parameters, 0) as u64);
+                },
+                META_DISK_SIZE => size = Some(le_u64(item(at, 8)?, 0)),
+                META_LOGICAL_SECTOR => sector_size = le_u32(item(at, 4)?, 0) as u64,
+                _ if le_u32(entry, 24) & METADATA_REQUIRED != 0 => {
+                    return Err(eyre!("Unknown required VHDX metadata item"));
+                },
+                _ => (),
+            }
+        }
+        let block_size = block_size.ok_or_else(|| eyre!("VHDX lacks file parameters"))?;
+        let size = size
+            .filter(|&size| size > 0)
+            .ok_or_else(|| eyre!("VHDX lacks virtual disk size"))?;
+        if !(MB..=256 * MB).contains(&block_size) || !block_size.is_power_of_two() {
+            return Err(eyre!("Invalid VHDX block size {block_size}"));
+        }
+        if sector_size != 512 && sector_size != 4096 {
+            return Err(eyre!("Invalid VHDX logical sector size {sector_size}"));
+        }
+
+        // Every `chunk` payload entries are followed by one sector bitmap entry
+        let chunk = (1 << 23) * sector_size / block_size;
+        let blocks = size.div_ceil(block_size);
+        let entries = (blocks + (blocks - 1) / chunk) as usize;
+        if entries * 8 > bat_len {
+            return Err(eyre!("VHDX BAT too small for virtual size"));
+        }
+
+        let raw = read_at(&mut file, bat_offset, entries * 8).context("failed to read BAT")?;
+        let bat = raw.chunks_exact(8).map(|entry| le_u64(entry, 0)).collect();
+
+        debug!("VHDX, {size} bytes, {block_size} bytes blocks, {sector_size} bytes sectors");
+
+        Ok(Self {
+            file,
+            size,
+            bat,
+            block_size,
+            chunk,
+            block: vec![0u8; block_size as usize],
+            block_no: None,
+            pos: 0,
+        })
+    }
+
+    fn load_block(&mut self, block_no: u64) -> io::Result<()> {
+        if self.block_no == Some(block_no) {
+            return Ok(());
+        }
+
+        let entry = self.bat[(block_no + block_no / self.chunk) as usize];
+        match entry & BAT_STATE_MASK {
+            PAYLOAD_FULLY_PRESENT => {
+                self.file.seek(SeekFrom::Start(entry & BAT_OFFSET_MASK))?;
+                self.file.read_exact(&mut self.block)?;
+            },
+            PAYLOAD_PARTIALLY_PRESENT => {
+                return Err(io::Error::other(
+                    "partially present VHDX block without parent",
+                ));
+            },
+            _ => self.block.fill(0),
+        }
+
+        self.block_no = Some(block_no);
+        Ok(())
+    }
+}
+
+impl Read for VhdxReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.load_block(self.pos / self.block_size)?;
+
+        let in_block = (self.pos % self.block_size) as usize;
+        let len = buf
+            .len()
+            .min(self.block.len() - in_block)
+            .min((self.size - self.pos) as usize);
+        buf[..len].copy_from_slice(&self.block[in_block..in_block + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    fn put(image: &mut [u8], at: u64, data: &[u8]) {
+        image[at as usize..at as usize + data.len()].copy_from_slice(data);
+    }
+
+    /// Points entry `index` of the region table at `offset` and `len`.
+    fn set_region(image: &mut [u8], index: usize, offset: u64, len: u32) {
+        let table = REGION_OFFSETS[0] as usize;
+        let regions = &mut image[table..table + REGION_SIZE];
+        let entry = 16 + index * 32;
+        regions[entry + 16..entry + 24].copy_from_slice(&offset.to_le_bytes());
+        regions[entry + 24..entry + 28].copy_from_slice(&len.to_le_bytes());
+        let crc = crc32c(regions, 4);
+        regions[4..8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// VHDX of two 1 MiB blocks with only the first one present. Second header is left
+    /// blank, as if damaged.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0u8; 4 * MB as usize];
+        put(&mut image, 0, FILE_SIGNATURE);
+
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[..4].copy_from_slice(HEADER_SIGNATURE);
+        header[8..16].copy_from_slice(&1u64.to_le_bytes());
+        header[66..68].copy_from_slice(&1u16.to_le_bytes());
+        let crc = crc32c(&header, 4);
+        header[4..8].copy_from_slice(&crc.to_le_bytes());
+        put(&mut image, HEADER_OFFSETS[0], &header);
+
+        let mut regions = vec![0u8; REGION_SIZE];
+        regions[..4].copy_from_slice(REGION_SIGNATURE);
+        regions[8..12].copy_from_slice(&2u32.to_le_bytes());
+        for (entry, (guid, offset, len)) in regions[16..]
+            .chunks_exact_mut(32)
+            .zip([(REGION_BAT, MB, MB), (REGION_METADATA, 2 * MB, 64 * KB)])
+        {
+            entry[..16].copy_from_slice(&guid);
+            entry[16..24].copy_from_slice(&offset.to_le_bytes());
+            entry[24..28].copy_from_slice(&(len as u32).to_le_bytes());
+            entry[28..32].copy_from_slice(&1u32.to_le_bytes());
+        }
+        let crc = crc32c(&regions, 4);
+        regions[4..8].copy_from_slice(&crc.to_le_bytes());
+        put(&mut image, REGION_OFFSETS[0], &regions);
+
+        put(
+            &mut image,
+            MB,
+            &((3 * MB) | PAYLOAD_FULLY_PRESENT).to_le_bytes(),
+        );
+
+        let meta = 2 * MB;
+        put(&mut image, meta, METADATA_SIGNATURE);
+        put(&mut image, meta + 10, &2u16.to_le_bytes());
+        put(&mut image, meta + 32, &META_FILE_PARAMETERS);
+        put(&mut image, meta + 48, &0x1000u32.to_le_bytes());
+        put(&mut image, meta + 64, &META_DISK_SIZE);
+        put(&mut image, meta + 80, &0x1010u32.to_le_bytes());
+        put(&mut image, meta + 0x1000, &(MB as u32).to_le_bytes());
+        put(&mut image, meta + 0x1010, &(2 * MB).to_le_bytes());
+
+        image[3 * MB as usize..].fill(0x5A);
+        image
+    }
+
+    fn read(data: &[u8]) -> Result<Vec<u8>> {
+        let file = temp_file(data);
+        let mut data = Vec::new();
+        VhdxReader::open(file.path())?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn reads_guest_contents() {
+        let data = read(&image()).unwrap();
+        assert_eq!(data.len(), 2 * MB as usize);
+        assert!(data[..MB as usize].iter().all(|&b| b == 0x5A));
+        assert!(data[MB as usize..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_image() {
+        let image = image();
+        assert!(read(&image[..100 * KB as usize]).is_err());
+        assert!(read(&image[..(3 * MB + MB / 2) as usize]).is_err());
+    }
+
+    #[test]
+    fn rejects_regions_outside_of_file() {
+        let mut bad = image();
+        set_region(&mut bad, 0, 64 * MB, MB as u32);
+        assert!(read(&bad).is_err());
+
+        let mut bad = image();
+        set_region(&mut bad, 1, u64::MAX - KB, 64 * KB as u32);
+        assert!(read(&bad).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_metadata() {
+        let mut bad = image();
+        set_region(&mut bad, 1, 2 * MB, 16);
+        assert!(read(&bad).is_err());
+
+        let mut bad = image();
+        put(&mut bad, 2 * MB + 80, &(64 * KB as u32 - 4).to_le_bytes());
+        assert!(read(&bad).is_err());
+
+        let mut bad = image();
+        put(&mut bad, 2 * MB + 48, &u32::MAX.to_le_bytes());
+        assert!(read(&bad).is_err());
+    }
+}
@@ -0,0 +1,512 @@
+use crate::tools::{le_u16, le_u32, le_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fs, io,
+    io::{Read, Seek, SeekFrom},
+    path,
+};
+
+const MAGIC: &[u8; 4] = b"KDMV";
+const SECTOR: u64 = 512;
+const GD_AT_END: u64 = u64::MAX;
+/// Grain size limits in sectors, 4 KiB as required by the specification up to 1 MiB.
+const MIN_GRAIN_SIZE: u64 = 8;
+const MAX_GRAIN_SIZE: u64 = 2048;
+/// Grain tables hold 512 entries, as the specification requires.
+const MAX_GTES_PER_GT: u32 = 512;
+
+const FLAG_COMPRESSED: u32 = 1 << 16;
+const FLAG_MARKERS: u32 = 1 << 17;
+
+const COMPRESSION_NONE: u16 = 0;
+const COMPRESSION_DEFLATE: u16 = 1;
+
+/// Virtual disk stored in a VMDK, presented as a raw stream.
+pub struct VmdkReader {
+    extents: Box<dyn Read + Send>,
+    left:    u64,
+}
+
+struct SparseHeader {
+    flags:       u32,
+    capacity:    u64,
+    grain_size:  u64,
+    desc_offset: u64,
+    desc_size:   u64,
+    gtes_per_gt: u32,
+    gd_offset:   u64,
+    compression: u16,
+}
+
+/// Whether `len` bytes from sector `offset` on lie within a file of `file_len` bytes.
+fn fits(file_len: u64, offset: u64, len: u64) -> bool {
+    offset
+        .checked_mul(SECTOR)
+        .and_then(|start| start.checked_add(len))
+        .is_some_and(|end| end <= file_len)
+}
+
+impl SparseHeader {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        if !buf.starts_with(MAGIC) {
+            return Err(eyre!("Not a VMDK sparse extent"));
+        }
+        let version = le_u32(buf, 4);
+        if !(1..=3).contains(&version) {
+            return Err(eyre!("Unsupported VMDK sparse extent version {version}"));
+        }
+
+        Ok(Self {
+            flags:       le_u32(buf, 8),
+            capacity:    le_u64(buf, 12),
+            grain_size:  le_u64(buf, 20),
+            desc_offset: le_u64(buf, 28),
+            desc_size:   le_u64(buf, 36),
+            gtes_per_gt: le_u32(buf, 44),
+            gd_offset:   le_u64(buf, 56),
+            compression: le_u16(buf, 77),
+        })
+    }
+}
+
+/// Hosted sparse extent (`monolithicSparse`, `twoGbMaxExtentSparse` and `streamOptimized`).
+struct SparseExtent {
+    file:     fs::File,
+    file_len: u64,
+    header:   SparseHeader,
+    gd:       Vec<u32>,
+    gt:       Vec<u32>,
+    gt_index: Option<usize>,
+    grain:    Vec<u8>,
+    grain_no: Option<u64>,
+    pos:      u64,
+}
+
+impl SparseExtent {
+    fn open(path: &path::Path) -> Result<Self> {
+        let mut file = fs::File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+        let mut buf = [0u8; SECTOR as usize];
+        file.read_exact(&mut buf)
+            .context("failed to read sparse header")?;
+        let mut header = SparseHeader::parse(&buf)?;
+        let file_len = file.metadata().context("failed to stat file")?.len();
+
+        if header.gd_offset == GD_AT_END {
+            // streamOptimized: real header is stored in the footer, before end-of-stream marker
+            let footer = file
+                .seek(SeekFrom::End(-2 * SECTOR as i64))
+                .context("failed to seek to footer")?;
+            file.read_exact(&mut buf).context("failed to read footer")?;
+            header = SparseHeader::parse(&buf).context("invalid footer")?;
+            if header.gd_offset == GD_AT_END || header.gd_offset * SECTOR >= footer {
+                return Err(eyre!("VMDK footer lacks grain directory"));
+            }
+        }
+
+        if !(MIN_GRAIN_SIZE..=MAX_GRAIN_SIZE).contains(&header.grain_size)
+            || !header.grain_size.is_power_of_two()
+        {
+            return Err(eyre!("Invalid VMDK grain size {}", header.grain_size));
+        }
+        if !(1..=MAX_GTES_PER_GT).contains(&header.gtes_per_gt) {
+            return Err(eyre!(
+                "Invalid VMDK grain table size {}",
+                header.gtes_per_gt
+            ));
+        }
+        if header.capacity.checked_mul(SECTOR).is_none() {
+            return Err(eyre!("Invalid VMDK capacity {}", header.capacity));
+        }
+        if header.flags & FLAG_COMPRESSED != 0 && header.compression != COMPRESSION_DEFLATE {
+            return Err(eyre!("Unsupported VMDK compression {}", header.compression));
+        }
+        if header.flags & FLAG_COMPRESSED == 0 && header.compression != COMPRESSION_NONE {
+            warn!("VMDK declares compression without compressed grains flag");
+        }
+
+        let gt_coverage = header.grain_size * header.gtes_per_gt as u64;
+        let gd_entries = header.capacity.div_ceil(gt_coverage);
+        if !fits(file_len, header.gd_offset, gd_entries * 4) {
+            return Err(eyre!("VMDK grain directory lies outside of the file"));
+        }
+        let mut gd_raw = vec![0u8; gd_entries as usize * 4];
+        file.seek(SeekFrom::Start(header.gd_offset * SECTOR))
+            .context("failed to seek to grain directory")?;
+        file.read_exact(&mut gd_raw)
+            .context("failed to read grain directory")?;
+        let gd = gd_raw
+            .chunks_exact(4)
+            .map(|entry| le_u32(entry, 0))
+            .collect();
+
+        debug!(
+            "VMDK sparse extent: {} sectors, {} sectors per grain, markers: {}",
+            header.capacity,
+            header.grain_size,
+            header.flags & FLAG_MARKERS != 0
+        );
+
+        let grain = vec![0u8; (header.grain_size * SECTOR) as usize];
+        Ok(Self {
+            file,
+            file_len,
+            header,
+            gd,
+            gt: Vec::new(),
+            gt_index: None,
+            grain,
+            grain_no: None,
+            pos: 0,
+        })
+    }
+
+    fn descriptor(&mut self) -> Result<Option<String>> {
+        if self.header.desc_offset == 0 || self.header.desc_size == 0 {
+            return Ok(None);
+        }
+        if !fits(
+            self.file_len,
+            self.header.desc_offset,
+            self.header.desc_size * SECTOR,
+        ) {
+            return Err(eyre!("VMDK descriptor lies outside of the file"));
+        }
+        let mut raw = vec![0u8; (self.header.desc_size * SECTOR) as usize];
+        self.file
+            .seek(SeekFrom::Start(self.header.desc_offset * SECTOR))
+            .context("failed to seek to descriptor")?;
+        self.file
+            .read_exact(&mut raw)
+            .context("failed to read descriptor")?;
+        let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        Ok(Some(String::from_utf8_lossy(&raw[..end]).to_string()))
+    }
+
+    fn load_gt(&mut self, index: usize) -> io::Result<()> {
+        if self.gt_index == Some(index) {
+            return Ok(());
+        }
+
+        let entries = self.header.gtes_per_gt as usize;
+        let offset = self.gd[index] as u64;
+        self.gt.clear();
+        if offset == 0 {
+            self.gt.resize(entries, 0);
+        } else {
+            if !fits(self.file_len, offset, entries as u64 * 4) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "VMDK grain table lies outside of the file",
+                ));
+            }
+            let mut raw = vec![0u8; entries * 4];
+            self.file.seek(SeekFrom::Start(offset * SECTOR))?;
+            self.file.read_exact(&mut raw)?;
+            self.gt
+                .extend(raw.chunks_exact(4).map(|entry| le_u32(entry, 0)));
+        }
+        self.gt_index = Some(index);
+        Ok(())
+    }
+
+    fn load_grain(&mut self, grain_no: u64) -> io::Result<()> {
+        if self.grain_no == Some(grain_no) {
+            return Ok(());
+        }
+
+        let per_gt = self.header.gtes_per_gt as u64;
+        self.load_gt((grain_no / per_gt) as usize)?;
+        let offset = self.gt[(grain_no % per_gt) as usize] as u64;
+
+        // 0 is an unallocated grain, 1 is an explicitly zeroed one
+        if offset <= 1 {
+            self.grain.fill(0);
+        } else if self.header.flags & FLAG_COMPRESSED != 0 {
+            let mut marker = [0u8; 12];
+            self.file.seek(SeekFrom::Start(offset * SECTOR))?;
+            self.file.read_exact(&mut marker)?;
+            let size = le_u32(&marker, 8) as u64;
+            if !fits(self.file_len, offset, marker.len() as u64 + size) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "VMDK compressed grain lies outside of the file",
+                ));
+            }
+            let mut compressed = vec![0u8; size as usize];
+            self.file.read_exact(&mut compressed)?;
+
+            // Last grain of the disk may be shorter than the grain size
+            let valid = ((self.header.capacity * SECTOR) - grain_no * self.grain.len() as u64)
+                .min(self.grain.len() as u64) as usize;
+            self.grain[valid..].fill(0);
+            flate2::read::ZlibDecoder::new(&compressed[..]).read_exact(&mut self.grain[..valid])?;
+        } else {
+            self.file.seek(SeekFrom::Start(offset * SECTOR))?;
+            self.file.read_exact(&mut self.grain)?;
+        }
+
+        self.grain_no = Some(grain_no);
+        Ok(())
+    }
+}
+
+impl Read for SparseExtent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.header.capacity * SECTOR;
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let grain_len = self.grain.len() as u64;
+        self.load_grain(self.pos / grain_len)?;
+
+        let in_grain = (self.pos % grain_len) as usize;
+        let len = buf
+            .len()
+            .min(self.grain.len() - in_grain)
+            .min((size - self.pos) as usize);
+        buf[..len].copy_from_slice(&self.grain[in_grain..in_grain + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+struct ExtentLine {
+    sectors: u64,
+    kind:    String,
+    file:    Option<String>,
+    offset:  u64,
+}
+
+/// Parses `RW 4192256 SPARSE "disk.vmdk"` style extent lines of the descriptor.
+fn parse_extents(descriptor: &str) -> Result<Vec<ExtentLine>> {
+    let mut extents = Vec::new();
+    for line in descriptor.lines().map(str::trim) {
+        if !(line.starts_with("RW ")
+            || line.starts_with("RDONLY ")
+            || line.starts_with("NOACCESS "))
+        {
+            continue;
+        }
+
+        let (head, file) = match line.split_once('"') {
+            Some((head, rest)) => {
+                let (file, tail) = rest
+                    .split_once('"')
+                    .ok_or_else(|| eyre!("Malformed VMDK extent line: {line}"))?;
+                (head, Some((file.to_string(), tail.trim())))
+            },
+            None => (line, None),
+        };
+
+        let mut fields = head.split_whitespace().skip(1);
+        let sectors = fields
+            .next()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| eyre!("Malformed VMDK extent line: {line}"))?;
+        let kind = fields
+            .next()
+            .ok_or_else(|| eyre!("Malformed VMDK extent line: {line}"))?
+            .to_ascii_uppercase();
+
+        let (file, offset) = match file {
+            Some((file, tail)) => (Some(file), tail.parse::<u64>().unwrap_or(0)),
+            None => (None, 0),
+        };
+        extents.push(ExtentLine {
+            sectors,
+            kind,
+            file,
+            offset,
+        });
+    }
+    Ok(extents)
+}
+
+fn create_type(descriptor: &str) -> Option<String> {
+    descriptor.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "createType").then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+impl VmdkReader {
+    pub fn open(path: &path::Path) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        fs::File::open(path)
+            .context("failed to open file")?
+            .read_exact(&mut magic)
+            .context("failed to read header")?;
+
+        if &magic == MAGIC {
+            let mut extent = SparseExtent::open(path)?;
+            if let Some(kind) = extent.descriptor()?.as_deref().and_then(create_type) {
+                debug!("VMDK create type {kind}");
+            }
+            let capacity = extent.header.capacity * SECTOR;
+            return Ok(Self {
+                extents: Box::new(extent),
+                left:    capacity,
+            });
+        }
+
+        let descriptor = fs::read(path).context("failed to read descriptor")?;
+        let descriptor = String::from_utf8_lossy(&descriptor);
+        if !descriptor.contains("# Disk DescriptorFile") {
+            return Err(eyre!("Not a VMDK image"));
+        }
+
+        let kind = create_type(&descriptor).unwrap_or_default();
+        debug!("VMDK create type {kind}");
+        if kind.starts_with("vmfs") && kind != "vmfs" {
+            return Err(eyre!("Unsupported VMDK type {kind}"));
+        }
+
+        let dir = path.parent().unwrap_or(path::Path::new("."));
+        let mut extents: Box<dyn Read + Send> = Box::new(io::empty());
+        let mut capacity = 0;
+
+        for line in parse_extents(&descriptor)? {
+            let len = line.sectors * SECTOR;
+            let extent: Box<dyn Read + Send> = match (line.kind.as_str(), line.file) {
+                ("ZERO", _) => Box::new(io::repeat(0)),
+                ("FLAT" | "VMFS", Some(file)) => {
+                    let mut f = fs::File::open(dir.join(&file))
+                        .with_context(|| format!("failed to open extent {file:?}"))?;
+                    f.seek(SeekFrom::Start(line.offset * SECTOR))
+                        .context("failed to seek extent")?;
+                    Box::new(f)
+                },
+                ("SPARSE", Some(file)) => Box::new(SparseExtent::open(&dir.join(file))?),
+                (kind, _) => return Err(eyre!("Unsupported VMDK extent type {kind}")),
+            };
+            extents = Box::new(extents.chain(extent.take(len)));
+            capacity += len;
+        }
+
+        if capacity == 0 {
+            return Err(eyre!("VMDK descriptor lists no extents"));
+        }
+
+        Ok(Self {
+            extents,
+            left: capacity,
+        })
+    }
+}
+
+impl Read for VmdkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let got = self.extents.read(buf)?;
+        if got == 0 && !buf.is_empty() && self.left > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "VMDK extent shorter than declared",
+            ));
+        }
+        self.left -= got as u64;
+        Ok(got)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    /// Monolithic sparse extent of two grains, 4 KiB each, with only the first one allocated.
+    fn sparse() -> Vec<u8> {
+        let mut image = vec![0u8; 16 * SECTOR as usize];
+        image[..4].copy_from_slice(MAGIC);
+        image[4..8].copy_from_slice(&1u32.to_le_bytes());
+        image[12..20].copy_from_slice(&16u64.to_le_bytes());
+        image[20..28].copy_from_slice(&8u64.to_le_bytes());
+        image[44..48].copy_from_slice(&512u32.to_le_bytes());
+        image[56..64].copy_from_slice(&1u64.to_le_bytes());
+        image[512..516].copy_from_slice(&2u32.to_le_bytes());
+        image[1024..1028].copy_from_slice(&8u32.to_le_bytes());
+        image[4096..].fill(0x5A);
+        image
+    }
+
+    fn read(path: &path::Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        VmdkReader::open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn reads_sparse_extent() {
+        let data = read(temp_file(&sparse()).path()).unwrap();
+        assert_eq!(data.len(), 8192);
+        assert!(data[..4096].iter().all(|&b| b == 0x5A));
+        assert!(data[4096..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reads_flat_extents_of_descriptor() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("disk-flat.vmdk"), [0x5Au8; 1024]).unwrap();
+        let descriptor = dir.path().join("disk.vmdk");
+        fs::write(
+            &descriptor,
+            "# Disk DescriptorFile\ncreateType=\"monolithicFlat\"\nRW 2 FLAT \"disk-flat.vmdk\" \
+             0\nRW 1 ZERO\n",
+        )
+        .unwrap();
+        let data = read(&descriptor).unwrap();
+        assert_eq!(data.len(), 1536);
+        assert!(data[..1024].iter().all(|&b| b == 0x5A));
+        assert!(data[1024..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rejects_truncated_image() {
+        let image = sparse();
+        for len in [100, 2 * SECTOR as usize, 10 * SECTOR as usize] {
+            assert!(read(temp_file(&image[..len]).path()).is_err());
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("disk-flat.vmdk"), [0x5Au8; 512]).unwrap();
+        let descriptor = dir.path().join("disk.vmdk");
+        fs::write(
+            &descriptor,
+            "# Disk DescriptorFile\nRW 2 FLAT \"disk-flat.vmdk\" 0\n",
+        )
+        .unwrap();
+        assert!(read(&descriptor).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        let fields: [(usize, &[u8]); 6] = [
+            (20, &2u64.to_le_bytes()),
+            (20, &4096u64.to_le_bytes()),
+            (44, &4096u32.to_le_bytes()),
+            (56, &(1u64 << 60).to_le_bytes()),
+            (12, &(1u64 << 60).to_le_bytes()),
+            (12, &u64::MAX.to_le_bytes()),
+        ];
+        for (at, value) in fields {
+            let mut image = sparse();
+            image[at..at + value.len()].copy_from_slice(value);
+            assert!(read(temp_file(&image).path()).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_compressed_grain_past_end() {
+        let mut image = sparse();
+        image[8..12].copy_from_slice(&FLAG_COMPRESSED.to_le_bytes());
+        image[77..79].copy_from_slice(&COMPRESSION_DEFLATE.to_le_bytes());
+        image[4104..4108].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read(temp_file(&image).path()).unwrap_err();
+        assert!(
+            err.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::InvalidData)
+        );
+    }
+}
@@ -1,94 +1,501 @@
-use color_eyre::eyre::{Context, Result};
+use crate::{
+    blkdev, bmap,
+    error::{Error, ResultExt},
+    gpt,
+    journal::Journal,
+    tools::{self, AlignedBuffer, PAGE_SIZE, eyre_unroll},
+    usb::Device,
+};
+use color_eyre::eyre::{Context, Result, eyre};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use sha2::Digest;
 use std::{
     fs,
-    io::{Read, Seek, Write},
-    os::unix::fs::OpenOptionsExt,
-    path,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    sync::{Arc, mpsc},
 };
 
-const BUF_SIZE: usize = 1024 * 1024;
-const PAGE_SIZE: usize = 4096;
-
-pub fn copy(len: usize, mut source: Box<dyn Read>, target: &path::Path) -> Result<()> {
-    let mut buf = [0u8; BUF_SIZE + PAGE_SIZE];
-    let page_shift = (PAGE_SIZE - ((buf.as_ptr() as usize) & (PAGE_SIZE - 1))) % PAGE_SIZE;
-    let aligned_buf = &mut buf[page_shift .. page_shift + BUF_SIZE];
-
-    let bar = indicatif::ProgressBar::new(len as u64)
-        .with_message("Writing")
-        .with_style(indicatif::ProgressStyle::with_template(
-            "{wide_bar} {msg} {bytes_per_sec}, ETA:{eta}",
-        )?);
-
-    let mut out = fs::OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(false)
-        .custom_flags(libc::O_DIRECT)
-        .open(target)
-        .context("failed to open output device {target}")?;
-
-    let mut file_sum = sha2::Sha256::new();
-    let mut data_left = len;
-
-    while data_left > 0 {
-        let read_block_size = data_left.clamp(0, BUF_SIZE);
-
-        if let Err(e) = source.read_exact(&mut aligned_buf[.. read_block_size]) {
-            warn!("failed to read image: {e}");
-            bar.finish_and_clear();
-            return Err(e.into());
+/// Image data written between flushes recorded in the journal.
+const CHECKPOINT: usize = 64 * 1024 * 1024;
+
+/// Device being written, with its own progress bar. A failure is kept rather than returned
+/// right away, so the other devices carry on.
+pub struct Target {
+    pub device:   Device,
+    pub bar:      indicatif::ProgressBar,
+    pub failure:  Option<Error>,
+    /// Image data up to here was written.
+    pub position: usize,
+    /// Bytes of zeroes not written, the device being zeroed up front.
+    pub skipped:  usize,
+    /// Bytes differing from what the device held, of the blocks compared before writing.
+    pub changed:  usize,
+    /// Bytes of the compared blocks rewritten for holding changes.
+    pub written:  usize,
+    /// Image data before this offset was written by an interrupted run.
+    pub resume:   usize,
+    out:          fs::File,
+    /// Transfer size suiting the device, see `Device::io_size`.
+    io_size:      usize,
+    /// Sector aligned buffer for transfers the image's own buffers can't be used for.
+    buf:          AlignedBuffer,
+    sparse:       bool,
+    incremental:  bool,
+    /// Offset last recorded in the journal.
+    checkpoint:   usize,
+}
+
+impl Target {
+    /// Opens the device for writing. `incremental` targets have each block read and compared
+    /// first, only differing blocks get written.
+    pub fn open(
+        device: Device,
+        multi: &indicatif::MultiProgress,
+        len: usize,
+        incremental: bool,
+    ) -> Result<Self> {
+        let out = fs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(false)
+            // Exclusive open fails on devices mounted behind our back, like in other namespaces
+            .custom_flags(libc::O_DIRECT | libc::O_EXCL)
+            .open(&device.dev)
+            .with_context(|| format!("Failed to open output device {:?}", device.dev))?;
+
+        let name = device
+            .dev
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let bar = multi.add(
+            indicatif::ProgressBar::new(len as u64)
+                .with_prefix(name)
+                .with_message(if incremental { "Updating" } else { "Writing" })
+                .with_finish(indicatif::ProgressFinish::AndLeave)
+                .with_style(
+                    indicatif::ProgressStyle::with_template(
+                        "{prefix:8} {wide_bar} {msg} {bytes_per_sec}, ETA:{eta}",
+                    )
+                    .unwrap(),
+                ),
+        );
+
+        Ok(Self::new(device, out, bar, incremental))
+    }
+
+    fn new(device: Device, out: fs::File, bar: indicatif::ProgressBar, incremental: bool) -> Self {
+        let io_size = device.io_size();
+        let sector = device.logical_block_size;
+        // Room for a transfer of `io_size` bytes not starting on a sector boundary
+        let buf = AlignedBuffer::with_layout(io_size + sector, sector.max(PAGE_SIZE));
+        Self {
+            device,
+            bar,
+            failure: None,
+            position: 0,
+            skipped: 0,
+            changed: 0,
+            written: 0,
+            out,
+            io_size,
+            buf,
+            sparse: false,
+            incremental,
+            resume: 0,
+            checkpoint: 0,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool { self.failure.is_none() }
+
+    fn fail(&mut self, e: Error) {
+        self.bar.abandon_with_message(format!("Failed: {e}"));
+        self.failure = Some(e);
+    }
+
+    /// Whole sectors covering `len` bytes at `offset`, as start and end offsets.
+    fn sectors(&self, offset: usize, len: usize) -> (usize, usize) {
+        let sector = self.device.logical_block_size;
+        (
+            offset - offset % sector,
+            (offset + len).next_multiple_of(sector),
+        )
+    }
+
+    /// Writes data not made of whole sectors, which `O_DIRECT` can't, `io_size` bytes at a
+    /// time. Sectors on the edges are read first, so their bytes outside of the data stay as
+    /// they were.
+    fn write_partial(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        let sector = self.device.logical_block_size;
+        for (n, piece) in data.chunks(self.io_size).enumerate() {
+            let offset = offset + n * self.io_size;
+            let (start, end) = self.sectors(offset, piece.len());
+            let at = offset - start;
+            let sectors = &mut self.buf.get_aligned_buf()[..end - start];
+            let last = sectors.len() - sector;
+
+            if at > 0 {
+                self.out
+                    .read_exact_at(&mut sectors[..sector], start as u64)
+                    .context("failed to read first sector")?;
+            }
+            if at + piece.len() < sectors.len() {
+                self.out
+                    .read_exact_at(&mut sectors[last..], (start + last) as u64)
+                    .context("failed to read last sector")?;
+            }
+            sectors[at..at + piece.len()].copy_from_slice(piece);
+            self.out
+                .write_all_at(sectors, start as u64)
+                .context("failed to write image")?;
+        }
+        Ok(())
+    }
+
+    /// Continues an interrupted write at `offset`. Data before it that still gets sent is
+    /// compared with the image, differing blocks get written again.
+    pub fn resume_at(&mut self, offset: usize) {
+        self.resume = offset;
+        self.checkpoint = offset;
+    }
+
+    /// Counts bytes of `data` differing from what the device holds at `offset`, reading
+    /// `io_size` bytes at a time.
+    fn count_changes(&mut self, offset: usize, data: &[u8]) -> Result<usize> {
+        let mut changes = 0;
+        for (n, piece) in data.chunks(self.io_size).enumerate() {
+            let offset = offset + n * self.io_size;
+            let (start, end) = self.sectors(offset, piece.len());
+            let sectors = &mut self.buf.get_aligned_buf()[..end - start];
+            self.out
+                .read_exact_at(sectors, start as u64)
+                .context("failed to read target for comparison")?;
+            changes += sectors[offset - start..]
+                .iter()
+                .zip(piece)
+                .filter(|(old, new)| old != new)
+                .count();
+        }
+        Ok(changes)
+    }
+
+    /// Zeroes the whole device without writing data, so blocks of zeroes need not be written
+    /// later. Devices unable to do that get written in full. Data of a resumed write is kept.
+    pub fn zero(&mut self) {
+        let start = self
+            .resume
+            .next_multiple_of(self.device.logical_block_size)
+            .min(self.device.size);
+        let len = self.device.size - start;
+        let zeroed = if self.device.write_zeroes_size > 0 {
+            blkdev::zero_out(&self.out, start, len)
+        } else if self.device.discard_zeroes {
+            blkdev::discard(&self.out, start, len)
+        } else {
+            info!(
+                "{dev:?} can't zero without writing, writing all the data",
+                dev = self.device.dev
+            );
+            return;
+        };
+
+        match zeroed {
+            Ok(()) => self.sparse = true,
+            Err(e) => warn!(
+                "Failed to zero {dev:?}, writing all the data: {e}",
+                dev = self.device.dev,
+                e = eyre_unroll(&e)
+            ),
+        }
+    }
+
+    fn write_block(&mut self, buf: &AlignedBuffer) -> Result<()> {
+        let data = &buf.aligned()[..buf.used];
+        // Resumed part wasn't zeroed, so its zeroes are compared like any other data
+        if self.incremental || buf.offset < self.resume {
+            let changes = self.count_changes(buf.offset, data)?;
+            if changes == 0 {
+                self.bar.inc(buf.used as u64);
+                return Ok(());
+            }
+            self.changed += changes;
+            self.written += buf.used;
+        } else if self.sparse && buf.zero {
+            self.skipped += buf.used;
+            self.bar.inc(buf.used as u64);
+            return Ok(());
+        }
+
+        let sector = self.device.logical_block_size;
+        if buf.offset.is_multiple_of(sector) && buf.used.is_multiple_of(sector) {
+            self.out
+                .write_all_at(data, buf.offset as u64)
+                .context("failed to write image")?;
+        } else {
+            self.write_partial(buf.offset, data)?;
+        }
+        self.bar.inc(buf.used as u64);
+        Ok(())
+    }
+
+    /// Flushes the device now and then, recording how far the data made it in the journal.
+    fn checkpoint(&mut self, end: usize, journal: Option<&Journal>) -> Result<()> {
+        let Some(journal) = journal else {
+            return Ok(());
+        };
+        if end < self.checkpoint + CHECKPOINT {
+            return Ok(());
+        }
+        self.out.sync_data().context("failed to flush device")?;
+        journal.record(&self.device.serial, end);
+        self.checkpoint = end;
+        Ok(())
+    }
+
+    /// Writes blocks until the channel closes or the user cancels, then flushes the device.
+    /// Each block is handed back to `done`, even after a failure, so the reader never runs
+    /// short of buffers. With a `journal` the progress is checkpointed, so the write can be
+    /// resumed if interrupted.
+    pub fn write(
+        &mut self,
+        blocks: mpsc::Receiver<Arc<AlignedBuffer>>,
+        done: mpsc::Sender<Arc<AlignedBuffer>>,
+        journal: Option<&Journal>,
+    ) {
+        for buf in blocks {
+            if self.is_ok() && !tools::cancelled() {
+                match self
+                    .write_block(&buf)
+                    .and_then(|()| self.checkpoint(buf.offset + buf.used, journal))
+                {
+                    Ok(()) => self.position = buf.offset + buf.used,
+                    Err(e) => self.fail(Error::Write(e)),
+                }
+            }
+            // Reader is gone once it hit an error, nothing to give back then
+            _ = done.send(buf);
+        }
+
+        if self.is_ok()
+            && let Err(e) = self.out.sync_all().context("failed to flush output file")
+        {
+            self.fail(Error::Write(e));
+        }
+    }
+
+    /// Forgets the device once the whole image got written, otherwise records how far the
+    /// write got. Failed devices keep their last checkpoint.
+    pub fn update_journal(&self, journal: &Journal, complete: bool) {
+        if !self.is_ok() {
+            return;
+        }
+        if complete {
+            journal.finish(&self.device.serial);
+        } else {
+            // Resumed part not compared yet is still there from before
+            journal.record(&self.device.serial, self.position.max(self.checkpoint));
+        }
+    }
+
+    /// Makes the kernel pick up the new partitions, one by one if the table can't be read as
+    /// a whole.
+    pub fn reread_partitions(&self) -> Result<()> {
+        let Err(e) = blkdev::reread_partitions(&self.out) else {
+            return Ok(());
         };
+        debug!(
+            "Updating partitions of {dev:?} one by one: {e}",
+            dev = self.device.dev,
+            e = eyre_unroll(&e)
+        );
+        let partitions = gpt::partitions(&self.out, self.device.logical_block_size)
+            .context("failed to read partitions")?;
+        blkdev::update_partitions(
+            &self.out,
+            self.device.dev.file_name().unwrap_or_default(),
+            &partitions,
+        )
+    }
+
+    /// Flushes and ejects the device, so it can be pulled out safely.
+    pub fn eject(&self) -> Result<()> { blkdev::eject(&self.out) }
 
-        if let Err(e) = out.write_all(&aligned_buf[.. read_block_size]) {
-            warn!("failed to write image: {e}");
-            bar.finish_and_clear();
-            return Err(e.into());
+    pub fn plan_relocation(&self, len: usize) -> Option<gpt::Relocation> {
+        if !self.is_ok() || len >= self.device.size {
+            return None;
         }
+        match gpt::Relocation::plan(
+            &self.out,
+            self.device.logical_block_size,
+            len,
+            self.device.size,
+        ) {
+            Ok(relocation) => relocation,
+            Err(e) => {
+                warn!(
+                    "Not relocating GPT on {dev:?}: {e}",
+                    dev = self.device.dev,
+                    e = eyre_unroll(&e)
+                );
+                None
+            },
+        }
+    }
+
+    /// Reads back given ranges of the target, returning SHA256 of the data as read and as it
+    /// would be after applying the GPT relocation.
+    fn hash(
+        &mut self,
+        ranges: &[bmap::Range],
+        relocation: Option<&gpt::Relocation>,
+    ) -> Result<([u8; 32], [u8; 32]), Error> {
+        let mut file_sum = sha2::Sha256::new();
+        let mut patched_sum = sha2::Sha256::new();
+
+        for range in ranges {
+            let mut offset = range.start;
+            while offset < range.end {
+                if tools::cancelled() {
+                    return Err(Error::Cancelled);
+                }
+                let read_block_size = (range.end - offset).min(self.io_size);
 
-        file_sum.update(&aligned_buf[.. read_block_size]);
+                // Reads cover whole sectors, of which only the range is hashed
+                let (start, end) = self.sectors(offset, read_block_size);
+                let sectors = &mut self.buf.get_aligned_buf()[..end - start];
+                self.out
+                    .read_exact_at(sectors, start as u64)
+                    .context("failed to read target for verification")
+                    .fail_as(Error::Write)?;
+                let data = &mut sectors[offset - start..][..read_block_size];
 
-        data_left -= read_block_size;
-        bar.inc(read_block_size as u64);
+                file_sum.update(&data[..]);
+                if let Some(relocation) = relocation {
+                    relocation.overlay(offset, data);
+                }
+                patched_sum.update(&data[..]);
+                offset += read_block_size;
+
+                self.bar.inc(read_block_size as u64);
+            }
+        }
+
+        Ok((file_sum.finalize().into(), patched_sum.finalize().into()))
     }
 
-    bar.set_position(0);
-    bar.set_message("Verifying");
+    fn relocate(&self, relocation: &gpt::Relocation) -> Result<(), Error> {
+        relocation
+            .apply(&self.out)
+            .wrap_err("Failed to relocate GPT")
+            .fail_as(Error::Gpt)
+    }
 
-    out.flush().context("failed to flush output file")?;
-    out.rewind().context("failed to rewind file")?;
+    /// Compares the written data with the image, then moves the backup GPT if asked to and
+    /// checks it once more. Without `expected_sum` only the relocation is done. Cancelling
+    /// stops it anywhere but in the middle of the relocation.
+    fn check(
+        &mut self,
+        ranges: &[bmap::Range],
+        expected_sum: Option<[u8; 32]>,
+        relocation: Option<&gpt::Relocation>,
+    ) -> Result<(), Error> {
+        if tools::cancelled() {
+            return Err(Error::Cancelled);
+        }
+        let Some(expected_sum) = expected_sum else {
+            return relocation.map_or(Ok(()), |relocation| self.relocate(relocation));
+        };
 
-    let source_sum = file_sum.finalize();
+        self.bar.reset();
+        self.bar.set_message("Verifying");
+        let (device_sum, patched_sum) = self.hash(ranges, relocation)?;
+        if !expected_sum.eq(&device_sum) {
+            return Err(Error::Verification);
+        }
 
-    let mut file_sum = sha2::Sha256::new();
-    data_left = len;
+        let Some(relocation) = relocation else {
+            return Ok(());
+        };
+        self.relocate(relocation)?;
 
-    while data_left > 0 {
-        let read_block_size = data_left.clamp(0, BUF_SIZE);
+        self.bar.reset();
+        self.bar.set_message("Verifying GPT");
+        let (device_sum, _) = self.hash(ranges, None)?;
+        let verified = relocation
+            .verify(&self.out)
+            .wrap_err("Relocated GPT verification failed")
+            .fail_as(Error::Gpt)?;
+        if !verified || !patched_sum.eq(&device_sum) {
+            return Err(Error::Gpt(eyre!("Relocated GPT verification failed")));
+        }
+        Ok(())
+    }
 
-        if let Err(e) = out.read_exact(&mut aligned_buf[.. read_block_size]) {
-            warn!("failed to read target for verification: {e}");
-            bar.finish_and_clear();
-            return Err(e.into());
+    pub fn verify(
+        &mut self,
+        ranges: &[bmap::Range],
+        expected_sum: Option<[u8; 32]>,
+        relocation: Option<&gpt::Relocation>,
+    ) {
+        if !self.is_ok() {
+            return;
+        }
+        match self.check(ranges, expected_sum, relocation) {
+            Ok(()) if expected_sum.is_some() => self.bar.finish_with_message("Verified"),
+            Ok(()) => self.bar.finish_with_message("Written"),
+            // Reported along with the other devices
+            Err(Error::Cancelled) => self.bar.abandon_with_message("Interrupted"),
+            Err(e) => self.fail(e),
         }
-        file_sum.update(&aligned_buf[.. read_block_size]);
-        data_left -= read_block_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::BUF_SIZE;
 
-        bar.inc(read_block_size as u64);
+    const SIZE: usize = 4 * BUF_SIZE;
+    const OLD: u8 = 0xEE;
+
+    /// Target backed by a file of `SIZE` bytes of `OLD`.
+    fn target(sector: usize, incremental: bool) -> Target {
+        let out = tempfile::tempfile().unwrap();
+        out.write_all_at(&vec![OLD; SIZE], 0).unwrap();
+        let device = Device::with_geometry(SIZE, sector);
+        Target::new(device, out, indicatif::ProgressBar::hidden(), incremental)
     }
 
-    bar.finish_and_clear();
+    #[test]
+    fn writes_partial_sectors() {
+        for sector in [512, 4096] {
+            let mut target = target(sector, false);
 
-    let device_sum = file_sum.finalize();
+            // More than one transfer, starting and ending inside sectors
+            let offset = sector + 100;
+            let data: Vec<u8> = (0..BUF_SIZE + sector).map(|n| n as u8).collect();
+            target.write_partial(offset, &data).unwrap();
 
-    if source_sum.eq(&device_sum) {
-        info!("Verifying successful");
-    } else {
-        warn!("Failed to verify image");
+            let mut written = vec![0u8; SIZE];
+            target.out.read_exact_at(&mut written, 0).unwrap();
+            let mut expected = vec![OLD; SIZE];
+            expected[offset..offset + data.len()].copy_from_slice(&data);
+            assert!(written == expected);
+        }
     }
 
-    Ok(())
+    #[test]
+    fn counts_changed_bytes() {
+        let mut target = target(4096, true);
+        let mut data = vec![OLD; BUF_SIZE + 4096];
+        assert_eq!(target.count_changes(100, &data).unwrap(), 0);
+
+        data[0] = 0;
+        data[BUF_SIZE + 1] = 0;
+        data[BUF_SIZE + 2] = 0;
+        assert_eq!(target.count_changes(100, &data).unwrap(), 3);
+    }
 }
@@ -0,0 +1,412 @@
+use crate::tools::{le_u16, le_u32, le_u64};
+use color_eyre::eyre::{Context, Result, eyre};
+#[allow(unused_imports)]
+use log::{debug, error, info, trace, warn};
+use std::{
+    fmt, fs, io,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path,
+};
+
+const EOCD_SIGNATURE: u32 = 0x0605_4B50;
+const EOCD64_LOCATOR_SIGNATURE: u32 = 0x0706_4B50;
+const EOCD64_SIGNATURE: u32 = 0x0606_4B50;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4B50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4B50;
+
+const EOCD_SIZE: usize = 22;
+const EOCD64_LOCATOR_SIZE: usize = 20;
+const EOCD64_SIZE: usize = 56;
+const CENTRAL_SIZE: usize = 46;
+const LOCAL_SIZE: usize = 30;
+const MAX_COMMENT: usize = 0xFFFF;
+
+const ZIP64_EXTRA: u16 = 0x0001;
+const FLAG_ENCRYPTED: u16 = 0x0001;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATE: u16 = 8;
+
+/// File stored in the archive, as described by the central directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name:     String,
+    pub size:     usize,
+    method:       u16,
+    flags:        u16,
+    crc:          u32,
+    compressed:   usize,
+    local_offset: usize,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool { self.name.ends_with('/') }
+
+    /// Replaces 32-bit fields saturated to `0xFFFFFFFF` with their zip64 extra field values.
+    fn apply_zip64(&mut self, extra: &[u8]) -> Result<()> {
+        let mut pos = 0;
+        while pos + 4 <= extra.len() {
+            let id = le_u16(extra, pos);
+            let len = le_u16(extra, pos + 2) as usize;
+            let data = extra
+                .get(pos + 4..pos + 4 + len)
+                .ok_or_else(|| eyre!("Truncated extra field of {:?}", self.name))?;
+            pos += 4 + len;
+            if id != ZIP64_EXTRA {
+                continue;
+            }
+
+            let mut values = data.chunks_exact(8).map(|value| le_u64(value, 0) as usize);
+            for field in [&mut self.size, &mut self.compressed, &mut self.local_offset] {
+                if *field == u32::MAX as usize {
+                    *field = values
+                        .next()
+                        .ok_or_else(|| eyre!("Incomplete zip64 field of {:?}", self.name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{name} ({size:.1} MiB)",
+            name = self.name,
+            size = self.size as f64 / (1024 * 1024) as f64
+        )
+    }
+}
+
+/// Finds the end of central directory record, returning the archive tail holding it, its
+/// position in there and the archive length.
+fn find_eocd(file: &mut fs::File) -> Result<(Vec<u8>, usize, usize)> {
+    let len = file.metadata().context("failed to stat file")?.len() as usize;
+    if len < EOCD_SIZE {
+        return Err(eyre!("Not a ZIP archive (too short)"));
+    }
+    let tail_len = len.min(EOCD_SIZE + MAX_COMMENT + EOCD64_LOCATOR_SIZE);
+    let mut tail = vec![0u8; tail_len];
+    file.seek(SeekFrom::Start((len - tail_len) as u64))
+        .context("failed to seek to end of archive")?;
+    file.read_exact(&mut tail)
+        .context("failed to read end of archive")?;
+
+    // Comment may contain anything, so look for the last record that spans it exactly
+    let at = (0..=tail_len - EOCD_SIZE)
+        .rev()
+        .find(|&at| {
+            tail.get(at..at + EOCD_SIZE).is_some_and(|eocd| {
+                le_u32(eocd, 0) == EOCD_SIGNATURE
+                    && at + EOCD_SIZE + le_u16(eocd, 20) as usize == tail_len
+            })
+        })
+        .ok_or_else(|| eyre!("Not a ZIP archive (end of central directory not found)"))?;
+    Ok((tail, at, len))
+}
+
+/// Lists all entries of the archive from its central directory.
+pub fn entries(path: &path::Path) -> Result<Vec<Entry>> {
+    let mut file = fs::File::open(path).context("failed to open file")?;
+    let (tail, at, len) = find_eocd(&mut file)?;
+    let eocd = &tail[at..];
+
+    let mut count = le_u16(eocd, 10) as usize;
+    let mut cd_size = le_u32(eocd, 12) as usize;
+    let mut cd_offset = le_u32(eocd, 16) as usize;
+    if le_u16(eocd, 4) != 0 || le_u16(eocd, 6) != 0 {
+        return Err(eyre!("Multi-volume ZIP archives are not supported"));
+    }
+
+    let locator = at
+        .checked_sub(EOCD64_LOCATOR_SIZE)
+        .map(|at| &tail[at..at + EOCD64_LOCATOR_SIZE])
+        .filter(|locator| le_u32(locator, 0) == EOCD64_LOCATOR_SIGNATURE);
+    if let Some(locator) = locator {
+        let mut eocd64 = [0u8; EOCD64_SIZE];
+        file.seek(SeekFrom::Start(le_u64(locator, 8)))
+            .context("failed to seek to zip64 directory end")?;
+        file.read_exact(&mut eocd64)
+            .context("failed to read zip64 directory end")?;
+        if le_u32(&eocd64, 0) != EOCD64_SIGNATURE {
+            return Err(eyre!("Invalid zip64 end of central directory"));
+        }
+        count = le_u64(&eocd64, 32) as usize;
+        cd_size = le_u64(&eocd64, 40) as usize;
+        cd_offset = le_u64(&eocd64, 48) as usize;
+    }
+
+    if cd_offset.checked_add(cd_size).is_none_or(|end| end > len) {
+        return Err(eyre!("Central directory lies outside of the archive"));
+    }
+    if count > cd_size / CENTRAL_SIZE {
+        return Err(eyre!("Corrupted central directory"));
+    }
+
+    let mut cd = vec![0u8; cd_size];
+    file.seek(SeekFrom::Start(cd_offset as u64))
+        .context("failed to seek to central directory")?;
+    file.read_exact(&mut cd)
+        .context("failed to read central directory")?;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut pos = 0;
+    for _ in 0..count {
+        let header = cd
+            .get(pos..pos + CENTRAL_SIZE)
+            .filter(|header| le_u32(header, 0) == CENTRAL_SIGNATURE)
+            .ok_or_else(|| eyre!("Corrupted central directory"))?;
+        let name_len = le_u16(header, 28) as usize;
+        let extra_len = le_u16(header, 30) as usize;
+        let comment_len = le_u16(header, 32) as usize;
+        let name_start = pos + CENTRAL_SIZE;
+        let extra_start = name_start + name_len;
+        let end = extra_start + extra_len + comment_len;
+        if end > cd.len() {
+            return Err(eyre!("Corrupted central directory"));
+        }
+
+        let mut entry = Entry {
+            name:         String::from_utf8_lossy(&cd[name_start..extra_start]).into_owned(),
+            size:         le_u32(header, 24) as usize,
+            method:       le_u16(header, 10),
+            flags:        le_u16(header, 8),
+            crc:          le_u32(header, 16),
+            compressed:   le_u32(header, 20) as usize,
+            local_offset: le_u32(header, 42) as usize,
+        };
+        entry.apply_zip64(&cd[extra_start..extra_start + extra_len])?;
+        entries.push(entry);
+        pos = end;
+    }
+
+    debug!("ZIP archive with {} entries", entries.len());
+    Ok(entries)
+}
+
+/// Contents of a single archive entry, checked against its CRC32 once fully read.
+pub struct ZipReader {
+    inner: Box<dyn Read>,
+    crc:   crc32fast::Hasher,
+    entry: Entry,
+    left:  usize,
+}
+
+impl ZipReader {
+    pub fn open(path: &path::Path, entry: &Entry) -> Result<Self> {
+        if entry.flags & FLAG_ENCRYPTED != 0 {
+            return Err(eyre!("Encrypted ZIP entries are not supported"));
+        }
+
+        let mut file = fs::File::open(path).context("failed to open file")?;
+        let mut header = [0u8; LOCAL_SIZE];
+        file.seek(SeekFrom::Start(entry.local_offset as u64))
+            .context("failed to seek to entry")?;
+        file.read_exact(&mut header)
+            .context("failed to read entry header")?;
+        if le_u32(&header, 0) != LOCAL_SIGNATURE {
+            return Err(eyre!("Invalid local header of {:?}", entry.name));
+        }
+        let skip = le_u16(&header, 26) as i64 + le_u16(&header, 28) as i64;
+        file.seek(SeekFrom::Current(skip))
+            .context("failed to seek to entry data")?;
+
+        let data = BufReader::new(file).take(entry.compressed as u64);
+        let inner: Box<dyn Read> = match entry.method {
+            METHOD_STORED => Box::new(data),
+            METHOD_DEFLATE => Box::new(flate2::bufread::DeflateDecoder::new(data)),
+            method => return Err(eyre!("Unsupported ZIP compression method {method}")),
+        };
+        debug!(
+            "Reading {name:?}, {compressed} bytes packed into {size}",
+            name = entry.name,
+            compressed = entry.compressed,
+            size = entry.size
+        );
+
+        Ok(Self {
+            inner,
+            crc: crc32fast::Hasher::new(),
+            entry: entry.clone(),
+            left: entry.size,
+        })
+    }
+}
+
+impl Read for ZipReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.left);
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{:?} ended prematurely", self.entry.name),
+            ));
+        }
+
+        self.crc.update(&buf[..read]);
+        self.left -= read;
+        if self.left == 0 && self.crc.clone().finalize() != self.entry.crc {
+            return Err(io::Error::other(format!(
+                "CRC32 mismatch of {:?}",
+                self.entry.name
+            )));
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::temp_file;
+
+    fn put16(buf: &mut Vec<u8>, val: u16) { buf.extend(val.to_le_bytes()) }
+
+    fn put32(buf: &mut Vec<u8>, val: u32) { buf.extend(val.to_le_bytes()) }
+
+    fn put64(buf: &mut Vec<u8>, val: u64) { buf.extend(val.to_le_bytes()) }
+
+    /// Archive with the directory described by a zip64 end record instead.
+    fn zip64(count: u64, cd_size: u64, cd_offset: u64) -> Vec<u8> {
+        let mut archive = archive();
+        let eocd = archive.split_off(archive.len() - EOCD_SIZE);
+        let eocd64_offset = archive.len() as u64;
+
+        put32(&mut archive, EOCD64_SIGNATURE);
+        put64(&mut archive, (EOCD64_SIZE - 12) as u64);
+        archive.extend([0u8; 12]);
+        put64(&mut archive, count);
+        put64(&mut archive, count);
+        put64(&mut archive, cd_size);
+        put64(&mut archive, cd_offset);
+
+        put32(&mut archive, EOCD64_LOCATOR_SIGNATURE);
+        put32(&mut archive, 0);
+        put64(&mut archive, eocd64_offset);
+        put32(&mut archive, 1);
+
+        archive.extend(eocd);
+        archive
+    }
+
+    /// Archive holding a directory and a stored image inside it.
+    fn archive() -> Vec<u8> {
+        let data = [0x5Au8; 1024];
+        let crc = crc32fast::hash(&data);
+        let mut archive = Vec::new();
+        let mut cd = Vec::new();
+        for (name, data) in [("dir/", &[][..]), ("dir/disk.img", &data[..])] {
+            let offset = archive.len() as u32;
+            let crc = if data.is_empty() { 0 } else { crc };
+            put32(&mut archive, LOCAL_SIGNATURE);
+            put16(&mut archive, 20);
+            archive.extend([0u8; 8]);
+            put32(&mut archive, crc);
+            put32(&mut archive, data.len() as u32);
+            put32(&mut archive, data.len() as u32);
+            put16(&mut archive, name.len() as u16);
+            put16(&mut archive, 0);
+            archive.extend(name.as_bytes());
+            archive.extend(data);
+
+            put32(&mut cd, CENTRAL_SIGNATURE);
+            put16(&mut cd, 20);
+            put16(&mut cd, 20);
+            put16(&mut cd, 0);
+            put16(&mut cd, METHOD_STORED);
+            cd.extend([0u8; 4]);
+            put32(&mut cd, crc);
+            put32(&mut cd, data.len() as u32);
+            put32(&mut cd, data.len() as u32);
+            put16(&mut cd, name.len() as u16);
+            cd.extend([0u8; 12]);
+            put32(&mut cd, offset);
+            cd.extend(name.as_bytes());
+        }
+
+        let cd_offset = archive.len() as u32;
+        archive.extend(&cd);
+        put32(&mut archive, EOCD_SIGNATURE);
+        archive.extend([0u8; 4]);
+        put16(&mut archive, 2);
+        put16(&mut archive, 2);
+        put32(&mut archive, cd.len() as u32);
+        put32(&mut archive, cd_offset);
+        put16(&mut archive, 0);
+        archive
+    }
+
+    #[test]
+    fn lists_and_reads_entries() {
+        let archive = temp_file(&archive());
+        let found = entries(archive.path()).unwrap();
+        let names: Vec<_> = found.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["dir/", "dir/disk.img"]);
+        assert!(found[0].is_dir());
+        assert_eq!(found[1].size, 1024);
+
+        let mut data = Vec::new();
+        ZipReader::open(archive.path(), &found[1])
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [0x5Au8; 1024]);
+    }
+
+    #[test]
+    fn rejects_truncated_archive() {
+        let archive = archive();
+        assert!(entries(temp_file(&archive[..archive.len() - 4]).path()).is_err());
+        assert!(entries(temp_file(&archive[archive.len() - EOCD_SIZE..]).path()).is_err());
+    }
+
+    #[test]
+    fn rejects_files_shorter_than_directory_end() {
+        assert!(entries(temp_file(b"PK\x05").path()).is_err());
+        assert!(entries(temp_file(&[0u8; EOCD_SIZE]).path()).is_err());
+    }
+
+    #[test]
+    fn rejects_directory_outside_of_archive() {
+        let mut bad = archive();
+        let eocd = bad.len() - EOCD_SIZE;
+        bad[eocd + 16..eocd + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(entries(temp_file(&bad).path()).is_err());
+
+        let mut bad = archive();
+        bad[eocd + 12..eocd + 16].copy_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+        assert!(entries(temp_file(&bad).path()).is_err());
+
+        let mut bad = archive();
+        bad[eocd + 10..eocd + 12].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert!(entries(temp_file(&bad).path()).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_zip64_directory() {
+        let archive = archive();
+        let eocd = &archive[archive.len() - EOCD_SIZE..];
+        let (cd_size, cd_offset) = (le_u32(eocd, 12) as u64, le_u32(eocd, 16) as u64);
+        assert_eq!(
+            entries(temp_file(&zip64(2, cd_size, cd_offset)).path())
+                .unwrap()
+                .len(),
+            2
+        );
+
+        for (count, cd_size, cd_offset) in [
+            (2, u64::MAX, cd_offset),
+            (2, cd_size, u64::MAX - 8),
+            (u64::MAX, cd_size, cd_offset),
+        ] {
+            assert!(entries(temp_file(&zip64(count, cd_size, cd_offset)).path()).is_err());
+        }
+    }
+}